@@ -1,94 +1,839 @@
 use bytes::Bytes;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use std::{collections::HashMap, io, sync::Arc};
+use std::{
+    collections::{hash_map::RandomState, HashMap, HashSet, VecDeque},
+    hash::{BuildHasher, Hasher},
+    io,
+    ops::Index,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
-    sync::RwLock,
+    sync::{Notify, RwLock},
 };
 
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+const DATABASES: usize = 16;
+
+pub struct Arg {
+    name: &'static str,
+    kind: &'static str,
+    token: Option<&'static str>,
+    optional: bool,
+    arguments: &'static [Arg],
+}
+
+impl Arg {
+    const fn new(name: &'static str, kind: &'static str) -> Arg {
+        Arg {
+            name,
+            kind,
+            token: None,
+            optional: false,
+            arguments: &[],
+        }
+    }
+
+    const fn token(mut self, token: &'static str) -> Arg {
+        self.token = Some(token);
+        self
+    }
+
+    const fn optional(mut self) -> Arg {
+        self.optional = true;
+        self
+    }
+
+    const fn arguments(mut self, arguments: &'static [Arg]) -> Arg {
+        self.arguments = arguments;
+        self
+    }
+}
+
+pub struct CommandSpec {
+    name: &'static str,
+    summary: &'static str,
+    since: &'static str,
+    group: &'static str,
+    // Whether the command may modify the keyspace.
+    write: bool,
+    // Number of arguments including the command name, negative for a minimum.
+    arity: i32,
+    arguments: &'static [Arg],
+}
+
+const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "PING",
+        summary: "Returns the server's liveliness response.",
+        since: "1.0.0",
+        group: "connection",
+        write: false,
+        arity: -1,
+        arguments: &[Arg::new("message", "string").optional()],
+    },
+    CommandSpec {
+        name: "ECHO",
+        summary: "Returns the given string.",
+        since: "1.0.0",
+        group: "connection",
+        write: false,
+        arity: 2,
+        arguments: &[Arg::new("message", "string")],
+    },
+    CommandSpec {
+        name: "HELLO",
+        summary: "Handshakes with the Redis server.",
+        since: "6.0.0",
+        group: "connection",
+        write: false,
+        arity: -1,
+        arguments: &[Arg::new("protover", "integer").optional()],
+    },
+    CommandSpec {
+        name: "SET",
+        summary: "Sets the string value of a key, ignoring its type. The key is created if it doesn't exist.",
+        since: "1.0.0",
+        group: "string",
+        write: true,
+        arity: -3,
+        arguments: &[
+            Arg::new("key", "key"),
+            Arg::new("value", "string"),
+            Arg::new("condition", "oneof").optional().arguments(&[
+                Arg::new("nx", "pure-token").token("NX"),
+                Arg::new("xx", "pure-token").token("XX"),
+            ]),
+            Arg::new("get", "pure-token").token("GET").optional(),
+            Arg::new("expiration", "oneof").optional().arguments(&[
+                Arg::new("seconds", "integer").token("EX"),
+                Arg::new("milliseconds", "integer").token("PX"),
+                Arg::new("unix-time-seconds", "unix-time").token("EXAT"),
+                Arg::new("unix-time-milliseconds", "unix-time").token("PXAT"),
+                Arg::new("keepttl", "pure-token").token("KEEPTTL"),
+            ]),
+        ],
+    },
+    CommandSpec {
+        name: "GET",
+        summary: "Returns the string value of a key.",
+        since: "1.0.0",
+        group: "string",
+        write: false,
+        arity: 2,
+        arguments: &[Arg::new("key", "key")],
+    },
+    CommandSpec {
+        name: "GETSET",
+        summary: "Returns the previous string value of a key after setting it to a new value.",
+        since: "1.0.0",
+        group: "string",
+        write: true,
+        arity: 3,
+        arguments: &[
+            Arg::new("key", "key"),
+            Arg::new("value", "string"),
+        ],
+    },
+    CommandSpec {
+        name: "MSET",
+        summary: "Atomically creates or modifies the string values of one or more keys.",
+        since: "1.0.1",
+        group: "string",
+        write: true,
+        arity: -3,
+        arguments: &[Arg::new("data", "block").arguments(&[
+            Arg::new("key", "key"),
+            Arg::new("value", "string"),
+        ])],
+    },
+    CommandSpec {
+        name: "MGET",
+        summary: "Atomically returns the string values of one or more keys.",
+        since: "1.0.0",
+        group: "string",
+        write: false,
+        arity: -2,
+        arguments: &[Arg::new("key", "key")],
+    },
+    CommandSpec {
+        name: "DEL",
+        summary: "Deletes one or more keys.",
+        since: "1.0.0",
+        group: "generic",
+        write: true,
+        arity: -2,
+        arguments: &[Arg::new("key", "key")],
+    },
+    CommandSpec {
+        name: "EXISTS",
+        summary: "Determines whether one or more keys exist.",
+        since: "1.0.0",
+        group: "generic",
+        write: false,
+        arity: -2,
+        arguments: &[Arg::new("key", "key")],
+    },
+    CommandSpec {
+        name: "INCR",
+        summary: "Increments the integer value of a key by one. Uses 0 as initial value if the key doesn't exist.",
+        since: "1.0.0",
+        group: "string",
+        write: true,
+        arity: 2,
+        arguments: &[Arg::new("key", "key")],
+    },
+    CommandSpec {
+        name: "DECR",
+        summary: "Decrements the integer value of a key by one. Uses 0 as initial value if the key doesn't exist.",
+        since: "1.0.0",
+        group: "string",
+        write: true,
+        arity: 2,
+        arguments: &[Arg::new("key", "key")],
+    },
+    CommandSpec {
+        name: "APPEND",
+        summary: "Appends a string to the value of a key. Creates the key if it doesn't exist.",
+        since: "2.0.0",
+        group: "string",
+        write: true,
+        arity: 3,
+        arguments: &[Arg::new("key", "key"), Arg::new("value", "string")],
+    },
+    CommandSpec {
+        name: "STRLEN",
+        summary: "Returns the length of a string value.",
+        since: "2.2.0",
+        group: "string",
+        write: false,
+        arity: 2,
+        arguments: &[Arg::new("key", "key")],
+    },
+    CommandSpec {
+        name: "GETRANGE",
+        summary: "Returns a substring of the string stored at a key.",
+        since: "2.4.0",
+        group: "string",
+        write: false,
+        arity: 4,
+        arguments: &[
+            Arg::new("key", "key"),
+            Arg::new("start", "integer"),
+            Arg::new("end", "integer"),
+        ],
+    },
+    CommandSpec {
+        name: "SETRANGE",
+        summary: "Overwrites a part of a string value with another by an offset. Creates the key if it doesn't exist.",
+        since: "2.2.0",
+        group: "string",
+        write: true,
+        arity: 4,
+        arguments: &[
+            Arg::new("key", "key"),
+            Arg::new("offset", "integer"),
+            Arg::new("value", "string"),
+        ],
+    },
+    CommandSpec {
+        name: "EXPIRE",
+        summary: "Sets the expiration time of a key in seconds.",
+        since: "1.0.0",
+        group: "generic",
+        write: true,
+        arity: 3,
+        arguments: &[
+            Arg::new("key", "key"),
+            Arg::new("seconds", "integer"),
+        ],
+    },
+    CommandSpec {
+        name: "PERSIST",
+        summary: "Removes the expiration time of a key.",
+        since: "2.2.0",
+        group: "generic",
+        write: true,
+        arity: 2,
+        arguments: &[Arg::new("key", "key")],
+    },
+    CommandSpec {
+        name: "KEYS",
+        summary: "Returns all key names that match a pattern.",
+        since: "1.0.0",
+        group: "generic",
+        write: false,
+        arity: 2,
+        arguments: &[Arg::new("pattern", "pattern")],
+    },
+    CommandSpec {
+        name: "OBJECT",
+        summary: "A container for object introspection commands.",
+        since: "2.2.3",
+        group: "generic",
+        write: false,
+        arity: -2,
+        arguments: &[Arg::new("subcommand", "oneof").arguments(&[
+            Arg::new("encoding", "key").token("ENCODING"),
+            Arg::new("idletime", "key").token("IDLETIME"),
+            Arg::new("refcount", "key").token("REFCOUNT"),
+            Arg::new("help", "pure-token").token("HELP"),
+        ])],
+    },
+    CommandSpec {
+        name: "TTL",
+        summary: "Returns the expiration time in seconds of a key.",
+        since: "1.0.0",
+        group: "generic",
+        write: false,
+        arity: 2,
+        arguments: &[Arg::new("key", "key")],
+    },
+    CommandSpec {
+        name: "PTTL",
+        summary: "Returns the expiration time in milliseconds of a key.",
+        since: "2.6.0",
+        group: "generic",
+        write: false,
+        arity: 2,
+        arguments: &[Arg::new("key", "key")],
+    },
+    CommandSpec {
+        name: "SELECT",
+        summary: "Changes the selected database.",
+        since: "1.0.0",
+        group: "connection",
+        write: false,
+        arity: 2,
+        arguments: &[Arg::new("index", "integer")],
+    },
+    CommandSpec {
+        name: "DBSIZE",
+        summary: "Returns the number of keys in the database.",
+        since: "1.0.0",
+        group: "server",
+        write: false,
+        arity: 1,
+        arguments: &[],
+    },
+    CommandSpec {
+        name: "FLUSHDB",
+        summary: "Removes all keys from the current database.",
+        since: "1.0.0",
+        group: "server",
+        write: true,
+        arity: -1,
+        arguments: &[Arg::new("flush-type", "oneof").optional().arguments(&[
+            Arg::new("async", "pure-token").token("ASYNC"),
+            Arg::new("sync", "pure-token").token("SYNC"),
+        ])],
+    },
+    CommandSpec {
+        name: "FLUSHALL",
+        summary: "Removes all keys from all databases.",
+        since: "1.0.0",
+        group: "server",
+        write: true,
+        arity: -1,
+        arguments: &[Arg::new("flush-type", "oneof").optional().arguments(&[
+            Arg::new("async", "pure-token").token("ASYNC"),
+            Arg::new("sync", "pure-token").token("SYNC"),
+        ])],
+    },
+    CommandSpec {
+        name: "EVAL",
+        summary: "Executes a server-side Lua script.",
+        since: "2.6.0",
+        group: "scripting",
+        write: false,
+        arity: -3,
+        arguments: &[
+            Arg::new("script", "string"),
+            Arg::new("numkeys", "integer"),
+        ],
+    },
+    CommandSpec {
+        name: "EVALSHA",
+        summary: "Executes a server-side Lua script by SHA1 digest.",
+        since: "2.6.0",
+        group: "scripting",
+        write: false,
+        arity: -3,
+        arguments: &[
+            Arg::new("sha1", "string"),
+            Arg::new("numkeys", "integer"),
+        ],
+    },
+    CommandSpec {
+        name: "SCRIPT",
+        summary: "Manages the server-side Lua script cache.",
+        since: "2.6.0",
+        group: "scripting",
+        write: false,
+        arity: -2,
+        arguments: &[Arg::new("subcommand", "oneof").arguments(&[
+            Arg::new("load", "string").token("LOAD"),
+            Arg::new("exists", "string").token("EXISTS"),
+            Arg::new("flush", "pure-token").token("FLUSH"),
+            Arg::new("help", "pure-token").token("HELP"),
+        ])],
+    },
+    CommandSpec {
+        name: "PFADD",
+        summary: "Adds elements to a HyperLogLog key. Creates the key if it doesn't exist.",
+        since: "2.8.9",
+        group: "hyperloglog",
+        write: true,
+        arity: -2,
+        arguments: &[
+            Arg::new("key", "key"),
+            Arg::new("element", "string").optional(),
+        ],
+    },
+    CommandSpec {
+        name: "PFCOUNT",
+        summary: "Returns the approximated cardinality of the set(s) observed by the HyperLogLog key(s).",
+        since: "2.8.9",
+        group: "hyperloglog",
+        write: false,
+        arity: -2,
+        arguments: &[Arg::new("key", "key")],
+    },
+    CommandSpec {
+        name: "PFMERGE",
+        summary: "Merges one or more HyperLogLog values into a single key.",
+        since: "2.8.9",
+        group: "hyperloglog",
+        write: true,
+        arity: -2,
+        arguments: &[
+            Arg::new("destkey", "key"),
+            Arg::new("sourcekey", "key").optional(),
+        ],
+    },
+    CommandSpec {
+        name: "HSET",
+        summary: "Creates or modifies the value of a field in a hash.",
+        since: "2.0.0",
+        group: "hash",
+        write: true,
+        arity: -4,
+        arguments: &[
+            Arg::new("key", "key"),
+            Arg::new("data", "block").arguments(&[
+                Arg::new("field", "string"),
+                Arg::new("value", "string"),
+            ]),
+        ],
+    },
+    CommandSpec {
+        name: "HGET",
+        summary: "Returns the value of a field in a hash.",
+        since: "2.0.0",
+        group: "hash",
+        write: false,
+        arity: 3,
+        arguments: &[Arg::new("key", "key"), Arg::new("field", "string")],
+    },
+    CommandSpec {
+        name: "HGETALL",
+        summary: "Returns all fields and values in a hash.",
+        since: "2.0.0",
+        group: "hash",
+        write: false,
+        arity: 2,
+        arguments: &[Arg::new("key", "key")],
+    },
+    CommandSpec {
+        name: "LPUSH",
+        summary: "Prepends one or more elements to a list. Creates the key if it doesn't exist.",
+        since: "1.0.0",
+        group: "list",
+        write: true,
+        arity: -3,
+        arguments: &[
+            Arg::new("key", "key"),
+            Arg::new("element", "string"),
+        ],
+    },
+    CommandSpec {
+        name: "RPUSH",
+        summary: "Appends one or more elements to a list. Creates the key if it doesn't exist.",
+        since: "1.0.0",
+        group: "list",
+        write: true,
+        arity: -3,
+        arguments: &[
+            Arg::new("key", "key"),
+            Arg::new("element", "string"),
+        ],
+    },
+    CommandSpec {
+        name: "LRANGE",
+        summary: "Returns a range of elements from a list.",
+        since: "1.0.0",
+        group: "list",
+        write: false,
+        arity: 4,
+        arguments: &[
+            Arg::new("key", "key"),
+            Arg::new("start", "integer"),
+            Arg::new("stop", "integer"),
+        ],
+    },
+    CommandSpec {
+        name: "LLEN",
+        summary: "Returns the length of a list.",
+        since: "1.0.0",
+        group: "list",
+        write: false,
+        arity: 2,
+        arguments: &[Arg::new("key", "key")],
+    },
+    CommandSpec {
+        name: "DEBUG",
+        summary: "A container for debugging commands.",
+        since: "1.0.0",
+        group: "server",
+        write: false,
+        arity: -2,
+        arguments: &[Arg::new("subcommand", "oneof").arguments(&[
+            Arg::new("help", "pure-token").token("HELP"),
+            Arg::new("object", "key").token("OBJECT"),
+            Arg::new("sleep", "double").token("SLEEP"),
+            Arg::new("sleep-blocking", "double").token("SLEEP-BLOCKING"),
+            Arg::new("set-active-expire", "integer").token("SET-ACTIVE-EXPIRE"),
+        ])],
+    },
+    CommandSpec {
+        name: "CLIENT",
+        summary: "A container for client connection commands.",
+        since: "2.4.0",
+        group: "connection",
+        write: false,
+        arity: -2,
+        arguments: &[Arg::new("subcommand", "oneof").arguments(&[
+            Arg::new("pause", "integer").token("PAUSE"),
+            Arg::new("unpause", "pure-token").token("UNPAUSE"),
+            Arg::new("id", "pure-token").token("ID"),
+            Arg::new("kill", "oneof").token("KILL").arguments(&[
+                Arg::new("old-format", "string"),
+                Arg::new("new-format", "oneof").arguments(&[
+                    Arg::new("client-id", "integer").token("ID").optional(),
+                    Arg::new("addr", "string").token("ADDR").optional(),
+                    Arg::new("skipme", "string").token("SKIPME").optional(),
+                ]),
+            ]),
+            Arg::new("help", "pure-token").token("HELP"),
+        ])],
+    },
+    CommandSpec {
+        name: "LOLWUT",
+        summary: "Displays computer art and the Redis version.",
+        since: "5.0.0",
+        group: "server",
+        write: false,
+        arity: -1,
+        arguments: &[Arg::new("version", "integer").token("VERSION").optional()],
+    },
+    CommandSpec {
+        name: "INFO",
+        summary: "Returns information and statistics about the server.",
+        since: "1.0.0",
+        group: "server",
+        write: false,
+        arity: -1,
+        arguments: &[Arg::new("section", "string").optional()],
+    },
+    CommandSpec {
+        name: "COMMAND",
+        summary: "Returns detailed information about all commands.",
+        since: "2.8.13",
+        group: "server",
+        write: false,
+        arity: -1,
+        arguments: &[],
+    },
+];
+
+fn arg_docs(arg: &Arg) -> Value {
+    let mut docs = vec![
+        Value::BulkString(Bytes::from("name")),
+        Value::BulkString(Bytes::from(arg.name)),
+        Value::BulkString(Bytes::from("type")),
+        Value::BulkString(Bytes::from(arg.kind)),
+    ];
+    if let Some(token) = arg.token {
+        docs.push(Value::BulkString(Bytes::from("token")));
+        docs.push(Value::BulkString(Bytes::from(token)));
+    }
+    if arg.optional {
+        docs.push(Value::BulkString(Bytes::from("flags")));
+        docs.push(Value::Array(vec![Value::SimpleString(
+            "optional".to_string(),
+        )]));
+    }
+    if !arg.arguments.is_empty() {
+        docs.push(Value::BulkString(Bytes::from("arguments")));
+        docs.push(Value::Array(arg.arguments.iter().map(arg_docs).collect()));
+    }
+    Value::Array(docs)
+}
+
+fn command_docs(spec: &CommandSpec) -> Value {
+    let mut docs = vec![
+        Value::BulkString(Bytes::from("summary")),
+        Value::BulkString(Bytes::from(spec.summary)),
+        Value::BulkString(Bytes::from("since")),
+        Value::BulkString(Bytes::from(spec.since)),
+        Value::BulkString(Bytes::from("group")),
+        Value::BulkString(Bytes::from(spec.group)),
+    ];
+    if !spec.arguments.is_empty() {
+        docs.push(Value::BulkString(Bytes::from("arguments")));
+        docs.push(Value::Array(spec.arguments.iter().map(arg_docs).collect()));
+    }
+    Value::Array(docs)
+}
+
 #[derive(Clone, PartialEq, Debug)]
 pub enum Value {
     SimpleString(String),
-    BulkString(String),
+    BulkString(Bytes),
+    Integer(i64),
+    Error(String),
     Array(Vec<Value>),
+    Null,
+}
+
+const READ_CHUNK: usize = 16 * 1024;
+
+const DEFAULT_MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+
+const DEFAULT_PROTO_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+// Arrays nested deeper than this are rejected rather than risking the stack.
+const MAX_NESTING_DEPTH: usize = 64;
+
+// A header line still unterminated after this many bytes is refused instead of buffered.
+const MAX_INLINE_LEN: usize = 64 * 1024;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum ParseError {
+    #[error("Protocol error: invalid multibulk length")]
+    InvalidMultibulkLength,
+    #[error("Protocol error: invalid bulk length")]
+    InvalidBulkLength,
+    #[error("Protocol error: invalid integer")]
+    InvalidInteger,
+    #[error("Protocol error: expected an array of bulk strings")]
+    InvalidCommand,
+    #[error("Protocol error: arrays nested too deeply")]
+    NestingTooDeep,
+    #[error("Protocol error: expected '\\r\\n' after bulk string")]
+    MissingBulkTerminator,
+    #[error("Protocol error: too big inline request")]
+    TooBigInline,
+    // The buffer ends before the current frame does, more bytes are needed.
+    #[error("Protocol error: incomplete frame")]
+    Incomplete,
 }
 
 pub struct Parser {
     buf: Bytes,
     pos: usize,
+    max_multibulk_len: usize,
+    max_bulk_len: usize,
+    depth: usize,
+    // Buffer length a bulk string cut short needs before parsing can get further.
+    needed: usize,
 }
 
-fn extract_string(value: &Value) -> String {
+fn decimal_len(x: i64) -> usize {
+    x.unsigned_abs()
+        .checked_ilog10()
+        .map_or(1, |d| d as usize + 1)
+        + usize::from(x < 0)
+}
+
+impl Value {
+    // Exact number of bytes serialize produces, so the reply is allocated once.
+    pub fn serialized_len(&self) -> usize {
+        match self {
+            Value::SimpleString(x) | Value::Error(x) => x.len() + 3,
+            Value::BulkString(x) => decimal_len(x.len() as i64) + x.len() + 5,
+            Value::Integer(x) => decimal_len(*x) + 3,
+            Value::Array(x) => {
+                decimal_len(x.len() as i64) + 3 + x.iter().map(Value::serialized_len).sum::<usize>()
+            }
+            Value::Null => 5,
+        }
+    }
+}
+
+fn serialize(value: &Value) -> Vec<u8> {
+    let mut out = Vec::with_capacity(value.serialized_len());
+    write_value(value, &mut out);
+    out
+}
+
+fn write_value(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::SimpleString(x) => {
+            out.push(b'+');
+            out.extend_from_slice(x.as_bytes());
+            out.extend_from_slice(b"\r\n");
+        }
+        Value::BulkString(x) => {
+            out.extend_from_slice(format!("${}\r\n", x.len()).as_bytes());
+            out.extend_from_slice(x);
+            out.extend_from_slice(b"\r\n");
+        }
+        Value::Integer(x) => out.extend_from_slice(format!(":{}\r\n", x).as_bytes()),
+        Value::Error(x) => {
+            out.push(b'-');
+            out.extend_from_slice(x.as_bytes());
+            out.extend_from_slice(b"\r\n");
+        }
+        Value::Array(x) => {
+            out.extend_from_slice(format!("*{}\r\n", x.len()).as_bytes());
+            for item in x {
+                write_value(item, out);
+            }
+        }
+        Value::Null => out.extend_from_slice(b"$-1\r\n"),
+    }
+}
+
+fn extract_bytes(value: &Value) -> Bytes {
     match value {
-        Value::SimpleString(x) | Value::BulkString(x) => x.to_string(),
+        Value::SimpleString(x) => Bytes::copy_from_slice(x.as_bytes()),
+        Value::BulkString(x) => x.clone(),
         _ => panic!("String expected"),
     }
 }
 
+fn extract_string(value: &Value) -> String {
+    String::from_utf8_lossy(&extract_bytes(value)).into_owned()
+}
+
 impl Parser {
     pub fn new(buf: &[u8]) -> Parser {
+        Parser::with_max_multibulk_len(buf, DEFAULT_MAX_MULTIBULK_LEN)
+    }
+
+    pub fn with_max_multibulk_len(buf: &[u8], max_multibulk_len: usize) -> Parser {
+        Parser::with_limits(buf, max_multibulk_len, DEFAULT_PROTO_MAX_BULK_LEN)
+    }
+
+    pub fn with_limits(buf: &[u8], max_multibulk_len: usize, max_bulk_len: usize) -> Parser {
+        Parser::from_bytes(Bytes::copy_from_slice(buf), max_multibulk_len, max_bulk_len)
+    }
+
+    // Parses buf without copying it, bulk strings are slices of it.
+    pub fn from_bytes(buf: Bytes, max_multibulk_len: usize, max_bulk_len: usize) -> Parser {
         Parser {
-            buf: Bytes::copy_from_slice(buf),
+            buf,
             pos: 0,
+            max_multibulk_len,
+            max_bulk_len,
+            depth: 0,
+            needed: 0,
+        }
+    }
+
+    fn read_line(&mut self) -> Result<Vec<u8>, ParseError> {
+        let rest = &self.buf[self.pos..];
+        let end = match rest.iter().position(|&x| x == b'\n') {
+            Some(end) => end,
+            None if rest.len() > MAX_INLINE_LEN => return Err(ParseError::TooBigInline),
+            None => return Err(ParseError::Incomplete),
+        };
+        let mut data = rest[..end].to_vec();
+        self.pos += end + 1;
+        if data.last() == Some(&b'\r') {
+            data.pop();
         }
+        Ok(data)
     }
 
-    pub fn parse_value(&mut self) -> Value {
-        match self.buf[self.pos] {
+    fn take(&mut self, len: usize) -> Result<Bytes, ParseError> {
+        if self.buf.len() - self.pos < len {
+            self.needed = self.pos + len;
+            return Err(ParseError::Incomplete);
+        }
+        let data = self.buf.slice(self.pos..self.pos + len);
+        self.pos += len;
+        Ok(data)
+    }
+
+    pub fn parse_value(&mut self) -> Result<Value, ParseError> {
+        match *self.buf.get(self.pos).ok_or(ParseError::Incomplete)? {
             b'+' => {
                 self.pos += 1;
-                let mut data: Vec<u8> = Vec::<u8>::new();
-                while self.buf[self.pos] != b'\r' {
-                    data.push(self.buf[self.pos]);
-                    self.pos += 1;
-                }
-                self.pos += 2;
-                let s = String::from_utf8(data);
-                return Value::SimpleString(s.expect("ffddf"));
+                let s = String::from_utf8_lossy(&self.read_line()?).into_owned();
+                Ok(Value::SimpleString(s))
+            }
+            b'-' => {
+                self.pos += 1;
+                let s = String::from_utf8_lossy(&self.read_line()?).into_owned();
+                Ok(Value::Error(s))
+            }
+            b':' => {
+                self.pos += 1;
+                let x = parse_i64(&self.read_line()?).map_err(|_| ParseError::InvalidInteger)?;
+                Ok(Value::Integer(x))
+            }
+            b'$' if self.buf[self.pos..].starts_with(b"$-1\r\n") => {
+                self.pos += 5;
+                Ok(Value::Null)
             }
             b'$' => {
-                self.pos += 4;
-                let mut data: Vec<u8> = Vec::<u8>::new();
-                while self.buf[self.pos] != b'\r' {
-                    data.push(self.buf[self.pos]);
-                    self.pos += 1;
+                self.pos += 1;
+                let len: usize = String::from_utf8(self.read_line()?)
+                    .ok()
+                    .and_then(|x| x.parse::<usize>().ok())
+                    .filter(|&x| x <= self.max_bulk_len)
+                    .ok_or(ParseError::InvalidBulkLength)?;
+                // Nothing is allocated for the body until all of it has been read.
+                let data = self.take(len)?;
+                if self.take(2)? != b"\r\n"[..] {
+                    return Err(ParseError::MissingBulkTerminator);
                 }
-                self.pos += 2;
-                let s = String::from_utf8(data);
-                return Value::BulkString(s.expect("ffddf"));
+                Ok(Value::BulkString(data))
             }
             b'*' => {
                 self.pos += 1;
-                let mut positive = true;
-                match self.buf[self.pos] {
-                    b'+' => {
-                        self.pos += 1;
-                    }
-                    b'-' => {
-                        self.pos += 1;
-                        positive = false;
-                    }
-                    _ => {}
+                let items: i64 = String::from_utf8(self.read_line()?)
+                    .ok()
+                    .and_then(|x| x.parse::<i64>().ok())
+                    .ok_or(ParseError::InvalidMultibulkLength)?;
+                if items > self.max_multibulk_len as i64 {
+                    return Err(ParseError::InvalidMultibulkLength);
                 }
-                let mut number_data = Vec::<u8>::new();
-                while self.buf[self.pos] != b'\r' {
-                    number_data.push(self.buf[self.pos]);
-                    self.pos += 1;
+                if self.depth == MAX_NESTING_DEPTH {
+                    return Err(ParseError::NestingTooDeep);
                 }
-                self.pos += 2;
-                let items: i64 = String::from_utf8(number_data)
-                    .expect("error")
-                    .parse::<i64>()
-                    .expect("error");
-                let mut array = Vec::<Value>::new();
-                for _ in 0..items {
-                    array.push(self.parse_value());
-                }
-                return Value::Array(array);
+                self.depth += 1;
+                let array = (0..items)
+                    .map(|_| self.parse_value())
+                    .collect::<Result<Vec<Value>, ParseError>>();
+                self.depth -= 1;
+                Ok(Value::Array(array?))
             }
             _ => {
-                panic!("Not supported {}", self.buf[self.pos]);
+                let line = self.read_line()?;
+                let args = line
+                    .split(u8::is_ascii_whitespace)
+                    .filter(|x| !x.is_empty())
+                    .map(|x| Value::BulkString(Bytes::copy_from_slice(x)))
+                    .collect();
+                Ok(Value::Array(args))
             }
         }
     }
@@ -97,257 +842,4938 @@ impl Parser {
 fn get_command(val: Value) -> (String, Vec<Value>) {
     match val {
         Value::Array(v) => {
-            let first: Value = v[0].clone();
             let rest: Vec<Value> = v.split_first().expect("error").1.to_vec();
-            match first {
-                Value::SimpleString(x) | Value::BulkString(x) => (x, rest),
-                _ => panic!("Not a string"),
-            }
+            (extract_string(&v[0]), rest)
         }
         _ => panic!("Not a command"),
     }
 }
 
-fn get_time() -> u128 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards")
-        .as_millis()
+fn parse_i64(data: &[u8]) -> Result<i64, String> {
+    let error = || "-ERR value is not an integer or out of range\r\n".to_string();
+    let digits = data.strip_prefix(b"-").unwrap_or(data);
+    let valid = match digits {
+        [b'0'] => digits.len() == data.len(),
+        [b'1'..=b'9', rest @ ..] => rest.iter().all(u8::is_ascii_digit),
+        _ => false,
+    };
+    if !valid {
+        return Err(error());
+    }
+    std::str::from_utf8(data)
+        .expect("ascii digits")
+        .parse::<i64>()
+        .map_err(|_| error())
 }
 
-async fn read(stream: &mut TcpStream, buffer: &mut [u8]) -> usize {
-    let mut total = 0;
-    loop {
-        let read = stream.read(&mut buffer[total..]);
-        match read.await {
-            Ok(0) => {
-                println!("Read 0 chars. Breaking");
-                break;
-            }
-            Ok(n) => {
-                total += n;
-                println!("Read {} chars", n);
-                break;
-            }
-            Err(e) => {
-                panic!("Unable to read stream: {}", e);
-            }
-        }
+fn parse_f64(data: &[u8]) -> Result<f64, String> {
+    let error = || "-ERR value is not a valid float\r\n".to_string();
+    if data.is_empty()
+        || data[0].is_ascii_whitespace()
+        || data[data.len() - 1].is_ascii_whitespace()
+    {
+        return Err(error());
+    }
+    match std::str::from_utf8(data).map(|x| x.parse::<f64>()) {
+        Ok(Ok(value)) if !value.is_nan() => Ok(value),
+        _ => Err(error()),
     }
-    return total;
 }
 
-pub struct StoredValue {
-    value: String,
-    expiry: u128,
+fn sha1_hex(data: &[u8]) -> String {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (x, y) in h.iter_mut().zip([a, b, c, d, e]) {
+            *x = x.wrapping_add(y);
+        }
+    }
+    h.iter().map(|x| format!("{:08x}", x)).collect()
 }
 
-async fn handle_command(
-    command: (String, Vec<Value>),
-    store: Arc<RwLock<HashMap<String, StoredValue>>>,
-) -> String {
-    match command.0.to_ascii_uppercase().as_str() {
-        "PING" => "+PONG\r\n".to_string(),
-        "ECHO" => format!(
-            "+{}\r\n",
-            command
-                .1
-                .iter()
-                .map(extract_string)
-                .collect::<Vec<String>>()
-                .join("")
-        ),
-        "SET" => {
-            let cmd = &command.1;
-            let key = extract_string(cmd.get(0).expect("ab"));
-            let value = extract_string(cmd.get(1).expect("ab"));
-            let mut to_add = Duration::from_secs(3600 * 24 * 365);
-            if cmd.len() == 4 {
-                let key = extract_string(cmd.get(2).expect("ab"));
-                assert!(key.to_ascii_uppercase() == "PX");
-                let expiry = extract_string(cmd.get(3).expect("ab"));
-                to_add = Duration::from_millis(expiry.parse::<u64>().expect("fdff"));
-            }
-            let expiration = get_time() + to_add.as_millis();
-
-            let mut writable = store.write().await;
-            writable.insert(
-                key,
-                StoredValue {
-                    value: value,
-                    expiry: expiration,
-                },
-            );
+fn string_encoding(value: &[u8]) -> &'static str {
+    if value.len() <= 20 && parse_i64(value).is_ok() {
+        "int"
+    } else if value.len() <= 44 {
+        "embstr"
+    } else {
+        "raw"
+    }
+}
 
-            "+OK\r\n".to_string()
+fn glob_match(pattern: &[u8], string: &[u8], nocase: bool) -> bool {
+    let eq = |a: u8, b: u8| {
+        if nocase {
+            a.eq_ignore_ascii_case(&b)
+        } else {
+            a == b
         }
-        "GET" => {
-            let key = command.1.get(0).expect("ab");
-            let str: String = extract_string(key);
-            let readable: tokio::sync::RwLockReadGuard<'_, HashMap<String, StoredValue>> =
-                store.read().await;
-            let val = readable.get(&str);
-            match val {
-                Some(x) => {
-                    if x.expiry < get_time() {
-                        "$-1\r\n".to_string()
-                    } else {
-                        format!("${}\r\n{}\r\n", x.value.len(), x.value)
+    };
+    match pattern.split_first() {
+        None => string.is_empty(),
+        Some((b'*', rest)) => (0..=string.len()).any(|i| glob_match(rest, &string[i..], nocase)),
+        Some((b'?', rest)) => !string.is_empty() && glob_match(rest, &string[1..], nocase),
+        Some((b'[', rest)) => {
+            let Some((&c, string_rest)) = string.split_first() else {
+                return false;
+            };
+            let (negate, mut class) = match rest.split_first() {
+                Some((b'^', class)) => (true, class),
+                _ => (false, rest),
+            };
+            let mut matched = false;
+            loop {
+                match class {
+                    [] => break,
+                    [b']', ..] => {
+                        class = &class[1..];
+                        break;
+                    }
+                    [b'\\', x, ..] => {
+                        matched |= eq(*x, c);
+                        class = &class[2..];
+                    }
+                    [start, b'-', end, ..] if *end != b']' => {
+                        let (low, high) = if start <= end {
+                            (*start, *end)
+                        } else {
+                            (*end, *start)
+                        };
+                        let c = if nocase { c.to_ascii_lowercase() } else { c };
+                        let in_range = |x: u8| x >= low && x <= high;
+                        matched |= in_range(c) || (nocase && in_range(c.to_ascii_uppercase()));
+                        class = &class[3..];
+                    }
+                    [x, ..] => {
+                        matched |= eq(*x, c);
+                        class = &class[1..];
                     }
                 }
-                None => "$-1\r\n".to_string(),
             }
+            matched != negate && glob_match(class, string_rest, nocase)
+        }
+        Some((b'\\', [x, rest @ ..])) => {
+            !string.is_empty() && eq(*x, string[0]) && glob_match(rest, &string[1..], nocase)
+        }
+        Some((x, rest)) => {
+            !string.is_empty() && eq(*x, string[0]) && glob_match(rest, &string[1..], nocase)
         }
-        _ => panic!("Command not recognized {}", command.0),
     }
 }
 
-async fn handle_client(
-    mut store: Arc<RwLock<HashMap<String, StoredValue>>>,
-    mut stream: TcpStream,
-) {
-    loop {
-        let mut buffer: [u8; 1024] = [0; 1024];
-        let n: usize = read(&mut stream, &mut buffer).await;
-        println!(
-            "Read string: {}\nEnd",
-            String::from_utf8((&buffer[..n]).to_vec()).expect("fdfd")
-        );
-        let command: (String, Vec<Value>) = get_command(Parser::new(&buffer[..n]).parse_value());
-        let res = handle_command(command, Arc::clone(&store)).await;
-        stream.write_all(res.as_bytes()).await;
+const HLL_P: u32 = 14;
+const HLL_REGISTERS: usize = 1 << HLL_P;
+const HLL_Q: u32 = 64 - HLL_P;
+const HLL_MAGIC: &str = "HYLL";
+const HLL_WRONGTYPE: &str = "-WRONGTYPE Key is not a valid HyperLogLog string value.\r\n";
+
+fn murmurhash64a(data: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4a7935bd1e995;
+    const R: u32 = 47;
+    let mut h = seed ^ (data.len() as u64).wrapping_mul(M);
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().expect("8 bytes"));
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, byte) in rest.iter().enumerate() {
+            h ^= (*byte as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
     }
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
 }
 
-#[tokio::main]
-async fn main() -> io::Result<()> {
-    println!("Logs from your program will appear here!");
-    let store = Arc::new(RwLock::new(HashMap::new()));
-    let listener = TcpListener::bind("127.0.0.1:6379").await?;
+// Registers are kept one per byte after the magic header, so the value stays a valid string.
+fn hll_registers(value: &[u8]) -> Option<Vec<u8>> {
+    let registers = value.strip_prefix(HLL_MAGIC.as_bytes())?;
+    if registers.len() != HLL_REGISTERS || registers.iter().any(|x| *x > HLL_Q as u8 + 1) {
+        return None;
+    }
+    Some(registers.to_vec())
+}
 
-    loop {
-        match listener.accept().await {
-            Ok((_stream, _)) => {
-                tokio::spawn(handle_client(Arc::clone(&store), _stream));
-                println!("accepted new connection");
+fn hll_value(registers: &[u8]) -> Bytes {
+    [HLL_MAGIC.as_bytes(), registers].concat().into()
+}
+
+fn hll_add(registers: &mut [u8], element: &[u8]) -> bool {
+    let hash = murmurhash64a(element, 0xadc83b19);
+    let index = (hash & (HLL_REGISTERS as u64 - 1)) as usize;
+    let count = ((hash >> HLL_P) | (1 << HLL_Q)).trailing_zeros() as u8 + 1;
+    if count > registers[index] {
+        registers[index] = count;
+        true
+    } else {
+        false
+    }
+}
+
+fn hll_count(registers: &[u8]) -> u64 {
+    let m = HLL_REGISTERS as f64;
+    let mut histogram = [0u32; 64];
+    for register in registers {
+        histogram[*register as usize] += 1;
+    }
+    let sigma = |mut x: f64| {
+        if x == 1.0 {
+            return f64::INFINITY;
+        }
+        let mut y = 1.0;
+        let mut z = x;
+        loop {
+            x *= x;
+            let previous = z;
+            z += x * y;
+            y += y;
+            if previous == z {
+                return z;
             }
-            Err(e) => {
-                println!("error: {}", e);
+        }
+    };
+    let tau = |mut x: f64| {
+        if x == 0.0 || x == 1.0 {
+            return 0.0;
+        }
+        let mut y = 1.0;
+        let mut z = 1.0 - x;
+        loop {
+            x = x.sqrt();
+            let previous = z;
+            y *= 0.5;
+            z -= (1.0 - x).powi(2) * y;
+            if previous == z {
+                return z / 3.0;
             }
         }
+    };
+    let q = HLL_Q as usize;
+    let mut z = m * tau((m - histogram[q + 1] as f64) / m);
+    for j in (1..=q).rev() {
+        z += histogram[j] as f64;
+        z *= 0.5;
     }
+    z += m * sigma(histogram[0] as f64 / m);
+    (0.5 / 2f64.ln() * m * m / z).round() as u64
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    #[test]
-    fn test_parse_string() {
-        let data = b"+ABC\r\n";
-        let mut p = Parser::new(data);
-        assert_eq!(p.parse_value(), Value::SimpleString(String::from("ABC")));
+fn hll_merge(registers: &mut [u8], other: &[u8]) {
+    for (register, other) in registers.iter_mut().zip(other) {
+        *register = (*register).max(*other);
     }
-    #[test]
-    fn test_parse_empty_string() {
-        let data = b"+\r\n";
-        let mut p = Parser::new(data);
-        assert_eq!(p.parse_value(), Value::SimpleString(String::from("")));
+}
+
+fn get_time() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis()
+}
+
+async fn read(stream: &mut (impl AsyncRead + Unpin), buffer: &mut Vec<u8>) -> usize {
+    buffer.reserve(READ_CHUNK);
+    // A read error closes the connection just like end of stream.
+    stream.read_buf(buffer).await.unwrap_or(0)
+}
+
+const WRONGTYPE: &str = "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n";
+
+// Strings built up server side are capped like bulk strings sent by clients.
+const STRING_TOO_LONG: &str = "-ERR string exceeds maximum allowed size (proto-max-bulk-len)\r\n";
+
+#[derive(Debug, PartialEq)]
+pub enum StoredData {
+    String(Bytes),
+    Hash(HashMap<Bytes, Bytes>),
+    List(VecDeque<Bytes>),
+}
+
+pub struct StoredValue {
+    value: StoredData,
+    expiry: Option<u128>,
+    last_access: AtomicU64,
+    // Redis' LFU counter: grows logarithmically with accesses and loses a point per idle minute.
+    frequency: AtomicU32,
+    // When the key was created, in ms. In-place updates keep it.
+    created_at: u64,
+    // Set once APPEND or SETRANGE edited the string in place, which Redis always reports as raw.
+    raw: bool,
+}
+
+const LFU_INIT_VAL: u32 = 5;
+
+const LFU_LOG_FACTOR: f64 = 10.0;
+
+const LFU_DECAY_MS: u64 = 60 * 1000;
+
+// Increments an LFU counter with probability 1 / ((counter - LFU_INIT_VAL) * LFU_LOG_FACTOR + 1),
+// so saturating at 255 takes about a million hits.
+fn lfu_log_incr(counter: u32) -> u32 {
+    if counter >= 255 {
+        return 255;
     }
-    #[test]
-    fn test_parse_bulk_string() {
-        let data = b"$5\r\nabcdef\r\n";
-        let mut p = Parser::new(data);
-        assert_eq!(p.parse_value(), Value::BulkString(String::from("abcdef")));
+    let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+    let p = 1.0 / (base * LFU_LOG_FACTOR + 1.0);
+    let r = (RandomState::new().build_hasher().finish() >> 11) as f64 / (1u64 << 53) as f64;
+    counter + u32::from(r < p)
+}
+
+const OBJ_SHARED_INTEGERS: i64 = 10000;
+
+const OBJ_SHARED_REFCOUNT: i64 = i32::MAX as i64;
+
+fn shared_integers() -> &'static [Bytes] {
+    static SHARED: OnceLock<Vec<Bytes>> = OnceLock::new();
+    SHARED.get_or_init(|| {
+        (0..OBJ_SHARED_INTEGERS)
+            .map(|i| Bytes::from(i.to_string()))
+            .collect()
+    })
+}
+
+// Small non-negative integers all point at one shared copy instead of
+// holding their own allocation.
+fn share_integer(value: Bytes) -> Bytes {
+    match parse_i64(&value) {
+        Ok(i) if (0..OBJ_SHARED_INTEGERS).contains(&i) => shared_integers()[i as usize].clone(),
+        _ => value,
     }
-    #[test]
-    fn test_parse_array() {
-        let data = b"*2\r\n+AB\r\n+CD\r\n";
-        let mut p = Parser::new(data);
-        assert_eq!(
-            p.parse_value(),
-            Value::Array(vec![
-                Value::SimpleString(String::from("AB")),
-                Value::SimpleString(String::from("CD"))
-            ])
-        );
+}
+
+impl StoredValue {
+    fn new(value: Bytes, expiry: Option<u128>) -> StoredValue {
+        StoredValue::with_data(StoredData::String(share_integer(value)), expiry)
     }
-    #[test]
-    fn test_parse_echo_command() {
-        let data = b"*2\r\n$4\r\nECHO\r\n$3\r\nhey\r\n";
-        let mut p = Parser::new(data);
-        assert_eq!(
-            p.parse_value(),
-            Value::Array(vec![
-                Value::BulkString(String::from("ECHO")),
-                Value::BulkString(String::from("hey"))
-            ])
-        );
+
+    fn with_data(value: StoredData, expiry: Option<u128>) -> StoredValue {
+        StoredValue {
+            value,
+            expiry,
+            last_access: AtomicU64::new(get_time() as u64),
+            frequency: AtomicU32::new(LFU_INIT_VAL),
+            created_at: get_time() as u64,
+            raw: false,
+        }
     }
-    #[test]
-    fn test_parse_echo_command_no_args() {
-        let data = b"*1\r\n$4\r\nECHO\r\n";
-        let mut p = Parser::new(data);
-        assert_eq!(
-            p.parse_value(),
-            Value::Array(vec![Value::BulkString(String::from("ECHO"))])
-        );
+
+    fn is_expired(&self) -> bool {
+        self.expiry.is_some_and(|expiry| expiry < get_time())
     }
-    #[test]
-    fn test_parse_get_command() {
-        let data = b"*2\r\n$3\r\nGET\r\n$3\r\nKEY\r\n";
-        let mut p = Parser::new(data);
-        assert_eq!(
-            p.parse_value(),
-            Value::Array(vec![
-                Value::BulkString(String::from("GET")),
-                Value::BulkString(String::from("KEY"))
-            ])
-        );
+
+    fn touch(&self) {
+        let now = get_time() as u64;
+        let idle = now.saturating_sub(self.last_access.swap(now, Ordering::Relaxed));
+        let periods = (idle / LFU_DECAY_MS).min(255) as u32;
+        let counter = self
+            .frequency
+            .load(Ordering::Relaxed)
+            .saturating_sub(periods);
+        self.frequency
+            .store(lfu_log_incr(counter), Ordering::Relaxed);
     }
-    #[test]
-    fn test_get_command_get() {
-        let data = Value::Array(vec![
-            Value::BulkString(String::from("GET")),
-            Value::BulkString(String::from("hey")),
-        ]);
-        assert_eq!(
-            get_command(data),
-            (
-                "GET".to_string(),
-                vec![Value::BulkString(String::from("hey"))]
-            )
-        );
+
+    fn idle_time(&self) -> u128 {
+        get_time().saturating_sub(self.last_access.load(Ordering::Relaxed) as u128)
     }
-    #[test]
-    fn test_get_command_set() {
-        let data = Value::Array(vec![
-            Value::BulkString(String::from("SET")),
-            Value::BulkString(String::from("hey")),
-            Value::BulkString(String::from("value")),
-        ]);
-        assert_eq!(
-            get_command(data),
-            (
-                "SET".to_string(),
-                vec![
-                    Value::BulkString(String::from("hey")),
-                    Value::BulkString(String::from("value"))
-                ]
-            )
-        );
+
+    fn age(&self) -> u128 {
+        get_time().saturating_sub(self.created_at as u128)
     }
-    #[test]
-    fn test_parse_command() {
-        let data = Value::Array(vec![
-            Value::BulkString(String::from("ECHO")),
-            Value::BulkString(String::from("hey")),
-        ]);
-        assert_eq!(
-            get_command(data),
+
+    // The string value, or None if the key holds another type.
+    fn string(&self) -> Option<&Bytes> {
+        match &self.value {
+            StoredData::String(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    fn encoding(&self) -> &'static str {
+        match &self.value {
+            StoredData::String(_) if self.raw => "raw",
+            StoredData::String(x) => string_encoding(x),
+            StoredData::Hash(_) => "hashtable",
+            StoredData::List(_) => "quicklist",
+        }
+    }
+
+    fn serialized_length(&self) -> usize {
+        match &self.value {
+            StoredData::String(x) => x.len(),
+            StoredData::Hash(x) => x.iter().map(|(k, v)| k.len() + v.len()).sum(),
+            StoredData::List(x) => x.iter().map(|v| v.len()).sum(),
+        }
+    }
+
+    fn refcount(&self) -> i64 {
+        let value = match self.string() {
+            Some(value) => value,
+            None => return 1,
+        };
+        match parse_i64(value) {
+            Ok(i)
+                if (0..OBJ_SHARED_INTEGERS).contains(&i)
+                    && shared_integers()[i as usize].as_ptr() == value.as_ptr() =>
+            {
+                OBJ_SHARED_REFCOUNT
+            }
+            _ => 1,
+        }
+    }
+}
+
+// One logical database. Besides the keys it keeps an index of the keys that
+// have a TTL, so active expiry can sample them without walking the keyspace.
+#[derive(Default)]
+pub struct Db {
+    entries: HashMap<Bytes, StoredValue>,
+    volatile: Vec<Bytes>,
+    // Position of each volatile key in `volatile`.
+    volatile_pos: HashMap<Bytes, usize>,
+}
+
+impl Db {
+    fn get(&self, key: &[u8]) -> Option<&StoredValue> {
+        self.entries.get(key)
+    }
+
+    // Expiry must be changed through set_expiry so the volatile index stays in sync.
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut StoredValue> {
+        self.entries.get_mut(key)
+    }
+
+    // The live value under the key, counted as an access. Expired keys read as missing.
+    fn lookup(&self, key: &[u8]) -> Option<&StoredValue> {
+        let value = self.entries.get(key).filter(|x| !x.is_expired())?;
+        value.touch();
+        Some(value)
+    }
+
+    // Like lookup, for commands that modify the value in place. An expired key is removed.
+    fn lookup_mut(&mut self, key: &[u8]) -> Option<&mut StoredValue> {
+        if self.entries.get(key).is_some_and(|x| x.is_expired()) {
+            self.remove(key);
+        }
+        let value = self.entries.get_mut(key)?;
+        value.touch();
+        Some(value)
+    }
+
+    fn lookup_or_insert_with(
+        &mut self,
+        key: Bytes,
+        f: impl FnOnce() -> StoredValue,
+    ) -> &mut StoredValue {
+        if self.lookup_mut(&key).is_none() {
+            self.insert(key.clone(), f());
+        }
+        self.entries.get_mut(&key).expect("inserted")
+    }
+
+    fn insert(&mut self, key: Bytes, value: StoredValue) -> Option<StoredValue> {
+        self.index(&key, value.expiry.is_some());
+        self.entries.insert(key, value)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<StoredValue> {
+        self.index(key, false);
+        self.entries.remove(key)
+    }
+
+    fn set_expiry(&mut self, key: &[u8], expiry: Option<u128>) {
+        if let Some(x) = self.entries.get_mut(key) {
+            x.expiry = expiry;
+            self.index(key, expiry.is_some());
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.volatile.clear();
+        self.volatile_pos.clear();
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&Bytes, &StoredValue)> {
+        self.entries.iter()
+    }
+
+    fn values(&self) -> impl Iterator<Item = &StoredValue> {
+        self.entries.values()
+    }
+
+    // Adds the key to or removes it from the volatile index in O(1).
+    fn index(&mut self, key: &[u8], volatile: bool) {
+        match (self.volatile_pos.get(key).copied(), volatile) {
+            (None, true) => {
+                let key = Bytes::copy_from_slice(key);
+                self.volatile_pos.insert(key.clone(), self.volatile.len());
+                self.volatile.push(key);
+            }
+            (Some(i), false) => {
+                self.volatile_pos.remove(key);
+                self.volatile.swap_remove(i);
+                if let Some(moved) = self.volatile.get(i) {
+                    self.volatile_pos.insert(moved.clone(), i);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Index<&[u8]> for Db {
+    type Output = StoredValue;
+
+    fn index(&self, key: &[u8]) -> &StoredValue {
+        &self.entries[key]
+    }
+}
+
+const ACTIVE_EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+const ACTIVE_EXPIRE_TIME_LIMIT: Duration = Duration::from_millis(25);
+const ACTIVE_EXPIRE_SAMPLE: usize = 20;
+
+// Samples up to ACTIVE_EXPIRE_SAMPLE keys from the volatile index starting at
+// `cursor` and removes the expired ones. Only volatile keys are visited, so the
+// cost is bounded by the sample size. Returns how many were sampled and removed.
+fn active_expire_cycle(store: &mut Db, cursor: &mut usize) -> (usize, usize) {
+    let len = store.volatile.len();
+    if *cursor >= len {
+        *cursor = 0;
+    }
+    let now = get_time();
+    let sampled = ACTIVE_EXPIRE_SAMPLE.min(len);
+    let expired: Vec<Bytes> = (0..sampled)
+        .map(|i| &store.volatile[(*cursor + i) % len])
+        .filter(|key| store.entries[&key[..]].expiry.is_some_and(|x| x < now))
+        .cloned()
+        .collect();
+    for key in &expired {
+        store.remove(key);
+    }
+    // Removed slots are refilled from the end of the index, so only the
+    // surviving keys are stepped over.
+    *cursor += sampled - expired.len();
+    (sampled, expired.len())
+}
+
+async fn active_expire(server: Arc<Server>) {
+    let mut cursors = vec![0; server.dbs.len()];
+    let mut interval = tokio::time::interval(ACTIVE_EXPIRE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if !server.active_expire.load(Ordering::Relaxed) {
+            continue;
+        }
+        let start = std::time::Instant::now();
+        for (db, cursor) in server.dbs.iter().zip(cursors.iter_mut()) {
+            loop {
+                let (sampled, expired) = active_expire_cycle(&mut *db.write().await, cursor);
+                // Keep going while more than 25% of the sample was expired.
+                if sampled == 0
+                    || expired * 4 <= sampled
+                    || start.elapsed() > ACTIVE_EXPIRE_TIME_LIMIT
+                {
+                    break;
+                }
+                tokio::task::yield_now().await;
+            }
+        }
+    }
+}
+
+// Client-supplied names are echoed in error replies, which must stay on one line.
+fn printable(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+// The reply to <name> HELP: a usage line, the given lines, then HELP itself.
+fn help_reply(name: &str, lines: &[&str]) -> Bytes {
+    let usage = format!(
+        "{} <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+        name
+    );
+    let lines = std::iter::once(usage.as_str())
+        .chain(lines.iter().copied())
+        .chain(["HELP", "    Print this help."])
+        .map(|x| Value::SimpleString(x.to_string()))
+        .collect();
+    serialize(&Value::Array(lines)).into()
+}
+
+fn wrong_arity(name: &str) -> Bytes {
+    format!(
+        "-ERR wrong number of arguments for '{}' command\r\n",
+        name.to_ascii_lowercase()
+    )
+    .into()
+}
+
+fn is_write_command(name: &str) -> bool {
+    COMMANDS.iter().any(|spec| spec.name == name && spec.write)
+}
+
+async fn wait_for_unpause(server: &Server, write: bool) {
+    loop {
+        let pause = *server.pause.lock().expect("pause");
+        let remaining = match pause {
+            Some((until, writes_only)) if write || !writes_only => until.saturating_sub(get_time()),
+            _ => 0,
+        };
+        if remaining == 0 {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(remaining.min(10) as u64)).await;
+    }
+}
+
+// State that belongs to one connection rather than to the whole server.
+#[derive(Default)]
+pub struct Client {
+    // Unique id assigned when the connection is accepted, 0 outside a connection.
+    id: u64,
+    // Index of the database selected with SELECT.
+    db: usize,
+    // Bytes the read buffer must hold before a partial frame is worth parsing again.
+    needed: usize,
+    // Replies produced but not yet written to the socket, in bytes.
+    pending_output: usize,
+}
+
+// What the server keeps about each open connection so CLIENT KILL can find it.
+pub struct ClientHandle {
+    addr: String,
+    kill: Arc<Notify>,
+}
+
+async fn handle_command(
+    command: (String, Vec<Value>),
+    server: Arc<Server>,
+    client: &mut Client,
+) -> Bytes {
+    let name = match server.config.resolve_command(&command.0) {
+        Some(name) => name,
+        None => return format!("-ERR unknown command '{}'\r\n", printable(&command.0)).into(),
+    };
+    if let Some(spec) = COMMANDS.iter().find(|spec| spec.name == name) {
+        let argc = command.1.len() as i32 + 1;
+        if (spec.arity > 0 && argc != spec.arity) || argc < -spec.arity {
+            // Reported under the name the client used, which differs for renamed commands.
+            return wrong_arity(&command.0);
+        }
+    }
+    if name != "CLIENT" {
+        wait_for_unpause(&server, is_write_command(&name)).await;
+    }
+    let store = &server.dbs[client.db];
+    match name.as_str() {
+        "PING" => serialize(&Value::SimpleString("PONG".to_string())).into(),
+        "ECHO" => serialize(&Value::BulkString(extract_bytes(&command.1[0]))).into(),
+        "HELLO" => {
+            if let Some(protover) = command.1.first() {
+                match parse_i64(&extract_bytes(protover)) {
+                    Ok(2) => {}
+                    Ok(_) => return "-NOPROTO unsupported protocol version\r\n".into(),
+                    Err(_) => {
+                        return "-ERR Protocol version is not an integer or out of range\r\n".into()
+                    }
+                }
+            }
+            if let Some(option) = command.1.get(1) {
+                return format!(
+                    "-ERR Syntax error in HELLO option '{}'\r\n",
+                    printable(&extract_string(option))
+                )
+                .into();
+            }
+            serialize(&Value::Array(vec![
+                Value::BulkString(Bytes::from("server")),
+                Value::BulkString(Bytes::from("redis")),
+                Value::BulkString(Bytes::from("version")),
+                Value::BulkString(Bytes::from(VERSION)),
+                Value::BulkString(Bytes::from("proto")),
+                Value::Integer(2),
+                Value::BulkString(Bytes::from("id")),
+                Value::Integer(client.id as i64),
+                Value::BulkString(Bytes::from("mode")),
+                Value::BulkString(Bytes::from("standalone")),
+                Value::BulkString(Bytes::from("role")),
+                Value::BulkString(Bytes::from("master")),
+                Value::BulkString(Bytes::from("modules")),
+                Value::Array(vec![]),
+            ]))
+            .into()
+        }
+        "SET" => {
+            let cmd = &command.1;
+            let key = extract_bytes(cmd.first().expect("ab"));
+            let value = extract_bytes(cmd.get(1).expect("ab"));
+            let mut expiry = None;
+            let mut keep_ttl = false;
+            let mut condition = None;
+            let mut get = false;
+            let mut i = 2;
+            while i < cmd.len() {
+                let option = extract_string(&cmd[i]).to_ascii_uppercase();
+                match option.as_str() {
+                    "EX" | "PX" | "EXAT" | "PXAT"
+                        if expiry.is_none() && !keep_ttl && i + 1 < cmd.len() =>
+                    {
+                        let amount = match parse_i64(&extract_bytes(&cmd[i + 1])) {
+                            Ok(amount) => amount,
+                            Err(e) => return e.into(),
+                        };
+                        let now = get_time() as i64;
+                        let at = match option.as_str() {
+                            "EX" => amount.checked_mul(1000).and_then(|ms| ms.checked_add(now)),
+                            "PX" => amount.checked_add(now),
+                            "EXAT" => amount.checked_mul(1000),
+                            _ => Some(amount),
+                        };
+                        match at {
+                            Some(at) if amount > 0 => expiry = Some(at as u128),
+                            _ => return "-ERR invalid expire time in 'set' command\r\n".into(),
+                        }
+                        i += 2;
+                    }
+                    "KEEPTTL" if expiry.is_none() && !keep_ttl => {
+                        keep_ttl = true;
+                        i += 1;
+                    }
+                    "NX" | "XX" if condition.is_none() => {
+                        condition = Some(option == "NX");
+                        i += 1;
+                    }
+                    "GET" if !get => {
+                        get = true;
+                        i += 1;
+                    }
+                    _ => return "-ERR syntax error\r\n".into(),
+                }
+            }
+
+            let mut writable = store.write().await;
+            let old = writable.get(&key).filter(|x| !x.is_expired());
+            // With GET the old value must be a string, checked before anything is written.
+            let reply = match old.map(|x| x.string()) {
+                _ if !get => Value::SimpleString("OK".to_string()),
+                Some(Some(old)) => Value::BulkString(old.clone()),
+                Some(None) => return WRONGTYPE.into(),
+                None => Value::Null,
+            };
+            if let Some(absent) = condition {
+                if old.is_some() == absent {
+                    return serialize(if get { &reply } else { &Value::Null }).into();
+                }
+            }
+            if keep_ttl {
+                expiry = old.and_then(|x| x.expiry);
+            }
+            writable.insert(key, StoredValue::new(value, expiry));
+
+            serialize(&reply).into()
+        }
+        "GET" => {
+            let key = command.1.first().expect("ab");
+            let str: Bytes = extract_bytes(key);
+            let readable: tokio::sync::RwLockReadGuard<'_, Db> = store.read().await;
+            let val = readable.lookup(&str);
+            let reply = match val {
+                Some(x) => match x.string() {
+                    Some(value) => Value::BulkString(value.clone()),
+                    None => return WRONGTYPE.into(),
+                },
+                None => Value::Null,
+            };
+            serialize(&reply).into()
+        }
+        "GETSET" => {
+            let key = extract_bytes(command.1.first().expect("ab"));
+            let value = extract_bytes(command.1.get(1).expect("ab"));
+            let mut writable = store.write().await;
+            let old = match writable.get(&key).filter(|x| !x.is_expired()) {
+                Some(x) => match x.string() {
+                    Some(old) => Value::BulkString(old.clone()),
+                    None => return WRONGTYPE.into(),
+                },
+                None => Value::Null,
+            };
+            // Like SET without KEEPTTL, the new value is persistent.
+            writable.insert(key, StoredValue::new(value, None));
+            serialize(&old).into()
+        }
+        "MSET" => {
+            if command.1.is_empty() || !command.1.len().is_multiple_of(2) {
+                return wrong_arity(&command.0);
+            }
+            let mut writable = store.write().await;
+            for pair in command.1.chunks(2) {
+                writable.insert(
+                    extract_bytes(&pair[0]),
+                    StoredValue::new(extract_bytes(&pair[1]), None),
+                );
+            }
+            "+OK\r\n".into()
+        }
+        "MGET" => {
+            let readable = store.read().await;
+            let values = command
+                .1
+                .iter()
+                .map(|key| match readable.lookup(&extract_bytes(key)) {
+                    Some(x) => x
+                        .string()
+                        .map_or(Value::Null, |value| Value::BulkString(value.clone())),
+                    _ => Value::Null,
+                })
+                .collect();
+            serialize(&Value::Array(values)).into()
+        }
+        "DEL" => {
+            let mut writable = store.write().await;
+            let deleted = command
+                .1
+                .iter()
+                .filter(|key| {
+                    writable
+                        .remove(&extract_bytes(key))
+                        .is_some_and(|x| !x.is_expired())
+                })
+                .count();
+            format!(":{}\r\n", deleted).into()
+        }
+        "INCR" | "DECR" => {
+            let key = extract_bytes(command.1.first().expect("ab"));
+            let delta = if name == "INCR" { 1 } else { -1 };
+            let mut writable = store.write().await;
+            let current = match writable.lookup_mut(&key).map(|x| x.string()) {
+                Some(Some(x)) => match parse_i64(x) {
+                    Ok(current) => current,
+                    Err(e) => return e.into(),
+                },
+                Some(None) => return WRONGTYPE.into(),
+                None => 0,
+            };
+            let value = match current.checked_add(delta) {
+                Some(value) => value,
+                None => return "-ERR increment or decrement would overflow\r\n".into(),
+            };
+            match writable.get_mut(&key) {
+                Some(x) => {
+                    x.value = StoredData::String(share_integer(value.to_string().into()));
+                    x.raw = false;
+                }
+                None => {
+                    writable.insert(key, StoredValue::new(value.to_string().into(), None));
+                }
+            }
+            format!(":{}\r\n", value).into()
+        }
+        "APPEND" => {
+            let key = extract_bytes(&command.1[0]);
+            let suffix = extract_bytes(&command.1[1]);
+            let mut writable = store.write().await;
+            let current = match writable.lookup_mut(&key).map(|x| x.string()) {
+                Some(Some(x)) => x.clone(),
+                Some(None) => return WRONGTYPE.into(),
+                None => Bytes::new(),
+            };
+            let len = current.len() + suffix.len();
+            if len > server.config.proto_max_bulk_len {
+                return STRING_TOO_LONG.into();
+            }
+            let mut value = Vec::with_capacity(len);
+            value.extend_from_slice(&current);
+            value.extend_from_slice(&suffix);
+            match writable.get_mut(&key) {
+                Some(x) => {
+                    x.value = StoredData::String(value.into());
+                    x.raw = true;
+                }
+                None => {
+                    writable.insert(key, StoredValue::new(value.into(), None));
+                }
+            }
+            format!(":{}\r\n", len).into()
+        }
+        "STRLEN" => {
+            let key = extract_bytes(&command.1[0]);
+            let readable = store.read().await;
+            match readable.lookup(&key).map(|x| x.string()) {
+                Some(Some(x)) => format!(":{}\r\n", x.len()).into(),
+                Some(None) => WRONGTYPE.into(),
+                None => ":0\r\n".into(),
+            }
+        }
+        "GETRANGE" => {
+            let key = extract_bytes(&command.1[0]);
+            let mut bounds = [0; 2];
+            for (bound, arg) in bounds.iter_mut().zip(&command.1[1..]) {
+                *bound = match parse_i64(&extract_bytes(arg)) {
+                    Ok(i) => i,
+                    Err(e) => return e.into(),
+                };
+            }
+            let readable = store.read().await;
+            let value = match readable.lookup(&key).map(|x| x.string()) {
+                Some(Some(x)) => x.clone(),
+                Some(None) => return WRONGTYPE.into(),
+                None => Bytes::new(),
+            };
+            let len = value.len() as i64;
+            let [start, end] = bounds;
+            if start < 0 && end < 0 && start > end {
+                return serialize(&Value::BulkString(Bytes::new())).into();
+            }
+            let start = if start < 0 { len + start } else { start }.max(0);
+            let end = if end < 0 { len + end } else { end }.max(0).min(len - 1);
+            let range = if start <= end {
+                value.slice(start as usize..=end as usize)
+            } else {
+                Bytes::new()
+            };
+            serialize(&Value::BulkString(range)).into()
+        }
+        "SETRANGE" => {
+            let key = extract_bytes(&command.1[0]);
+            let offset = match parse_i64(&extract_bytes(&command.1[1])) {
+                Ok(offset) if offset >= 0 => offset as usize,
+                Ok(_) => return "-ERR offset is out of range\r\n".into(),
+                Err(e) => return e.into(),
+            };
+            let patch = extract_bytes(&command.1[2]);
+            let mut writable = store.write().await;
+            let current = match writable.lookup_mut(&key).map(|x| x.string()) {
+                Some(Some(x)) => x.clone(),
+                Some(None) => return WRONGTYPE.into(),
+                None => Bytes::new(),
+            };
+            // An empty value changes nothing and does not create the key.
+            if patch.is_empty() {
+                return format!(":{}\r\n", current.len()).into();
+            }
+            if offset.saturating_add(patch.len()) > server.config.proto_max_bulk_len {
+                return STRING_TOO_LONG.into();
+            }
+            let mut value = current.to_vec();
+            if value.len() < offset + patch.len() {
+                value.resize(offset + patch.len(), 0);
+            }
+            value[offset..offset + patch.len()].copy_from_slice(&patch);
+            let len = value.len();
+            match writable.get_mut(&key) {
+                Some(x) => {
+                    x.value = StoredData::String(value.into());
+                    x.raw = true;
+                }
+                None => {
+                    // Unlike APPEND, SETRANGE builds a raw string even when it creates the key.
+                    let mut created = StoredValue::new(value.into(), None);
+                    created.raw = true;
+                    writable.insert(key, created);
+                }
+            }
+            format!(":{}\r\n", len).into()
+        }
+        "EXPIRE" => {
+            let key = extract_bytes(command.1.first().expect("ab"));
+            let seconds = match parse_i64(&extract_bytes(command.1.get(1).expect("ab"))) {
+                Ok(seconds) => seconds,
+                Err(e) => return e.into(),
+            };
+            let expiry = match seconds.checked_mul(1000) {
+                Some(ms) if ms <= 0 => None,
+                Some(ms) if ms.checked_add(get_time() as i64).is_some() => {
+                    Some(get_time() + ms as u128)
+                }
+                _ => return "-ERR invalid expire time in 'expire' command\r\n".into(),
+            };
+            let mut writable = store.write().await;
+            if writable.lookup(&key).is_none() {
+                return ":0\r\n".into();
+            }
+            match expiry {
+                Some(expiry) => writable.set_expiry(&key, Some(expiry)),
+                None => {
+                    writable.remove(&key);
+                }
+            }
+            ":1\r\n".into()
+        }
+        "PERSIST" => {
+            let key = extract_bytes(command.1.first().expect("ab"));
+            let mut writable = store.write().await;
+            match writable.lookup(&key) {
+                Some(x) if x.expiry.is_some() => {
+                    writable.set_expiry(&key, None);
+                    ":1\r\n".into()
+                }
+                _ => ":0\r\n".into(),
+            }
+        }
+        "KEYS" => {
+            let pattern = extract_bytes(&command.1[0]);
+            // Scans the whole keyspace under the read lock, like Redis does.
+            let readable = store.read().await;
+            let keys = readable
+                .iter()
+                .filter(|(key, x)| !x.is_expired() && glob_match(&pattern, key, false))
+                .map(|(key, _)| Value::BulkString(key.clone()))
+                .collect();
+            serialize(&Value::Array(keys)).into()
+        }
+        "OBJECT" => {
+            let subcommand = extract_string(&command.1[0]).to_ascii_uppercase();
+            if subcommand == "HELP" {
+                return help_reply(
+                    "OBJECT",
+                    &[
+                        "ENCODING <key>",
+                        "    Return the kind of internal representation used to store the value of <key>.",
+                        "IDLETIME <key>",
+                        "    Return the number of seconds since <key> was last accessed.",
+                        "REFCOUNT <key>",
+                        "    Return the number of references to the value of <key>.",
+                    ],
+                );
+            }
+            if !matches!(subcommand.as_str(), "ENCODING" | "IDLETIME" | "REFCOUNT") {
+                return format!(
+                    "-ERR unknown subcommand '{}'. Try OBJECT HELP.\r\n",
+                    printable(&extract_string(&command.1[0]))
+                )
+                .into();
+            }
+            if command.1.len() != 2 {
+                return wrong_arity(&format!("object|{}", subcommand));
+            }
+            let key = extract_bytes(&command.1[1]);
+            let readable = store.read().await;
+            let reply = match readable.get(&key).filter(|x| !x.is_expired()) {
+                None => Value::Null,
+                Some(x) => match subcommand.as_str() {
+                    "ENCODING" => Value::BulkString(Bytes::from(x.encoding())),
+                    "IDLETIME" => Value::Integer((x.idle_time() / 1000) as i64),
+                    _ => Value::Integer(x.refcount()),
+                },
+            };
+            serialize(&reply).into()
+        }
+        "TTL" | "PTTL" => {
+            let key = extract_bytes(command.1.first().expect("ab"));
+            let readable = store.read().await;
+            let ttl = match readable.get(&key).filter(|x| !x.is_expired()) {
+                None => -2,
+                Some(StoredValue { expiry: None, .. }) => -1,
+                Some(StoredValue {
+                    expiry: Some(expiry),
+                    ..
+                }) => {
+                    let remaining = expiry.saturating_sub(get_time()) as i64;
+                    if name == "TTL" {
+                        (remaining + 500) / 1000
+                    } else {
+                        remaining
+                    }
+                }
+            };
+            format!(":{}\r\n", ttl).into()
+        }
+        "EXISTS" => {
+            let readable = store.read().await;
+            let count = command
+                .1
+                .iter()
+                .filter(|key| readable.lookup(&extract_bytes(key)).is_some())
+                .count();
+            format!(":{}\r\n", count).into()
+        }
+        "SELECT" => {
+            let index = match parse_i64(&extract_bytes(command.1.first().expect("ab"))) {
+                Ok(index) => index,
+                Err(e) => return e.into(),
+            };
+            if index < 0 || index as usize >= server.dbs.len() {
+                return "-ERR DB index is out of range\r\n".into();
+            }
+            client.db = index as usize;
+            "+OK\r\n".into()
+        }
+        "DBSIZE" => {
+            let count = store
+                .read()
+                .await
+                .values()
+                .filter(|x| !x.is_expired())
+                .count();
+            format!(":{}\r\n", count).into()
+        }
+        "FLUSHDB" | "FLUSHALL" => {
+            // Memory is freed in place either way, so ASYNC is the same as SYNC.
+            match command
+                .1
+                .iter()
+                .map(extract_string)
+                .collect::<Vec<_>>()
+                .as_slice()
+            {
+                [] => {}
+                [mode]
+                    if mode.eq_ignore_ascii_case("ASYNC") || mode.eq_ignore_ascii_case("SYNC") => {}
+                _ => return "-ERR syntax error\r\n".into(),
+            }
+            if name == "FLUSHDB" {
+                store.write().await.clear();
+            } else {
+                for db in &server.dbs {
+                    db.write().await.clear();
+                }
+            }
+            "+OK\r\n".into()
+        }
+        "EVAL" => "-ERR This Redis build does not support scripting\r\n".into(),
+        "EVALSHA" => {
+            let sha = extract_string(command.1.first().expect("ab")).to_ascii_lowercase();
+            if server.scripts.lock().expect("scripts").contains(&sha) {
+                "-ERR This Redis build does not support scripting\r\n".into()
+            } else {
+                "-NOSCRIPT No matching script. Please use EVAL.\r\n".into()
+            }
+        }
+        "SCRIPT" => {
+            let subcommand = command.1.first().map(extract_string).unwrap_or_default();
+            let mut scripts = server.scripts.lock().expect("scripts");
+            match subcommand.to_ascii_uppercase().as_str() {
+                "HELP" => help_reply(
+                    "SCRIPT",
+                    &[
+                        "EXISTS <sha1> [<sha1> ...]",
+                        "    Return information about the existence of the scripts in the script cache.",
+                        "FLUSH",
+                        "    Flush the Lua scripts cache.",
+                        "LOAD <script>",
+                        "    Load a script into the scripts cache without executing it.",
+                    ],
+                ),
+                "LOAD" if command.1.len() != 2 => wrong_arity("script|load"),
+                "EXISTS" if command.1.len() < 2 => wrong_arity("script|exists"),
+                "LOAD" => {
+                    let sha = sha1_hex(&extract_bytes(command.1.get(1).expect("ab")));
+                    scripts.insert(sha.clone());
+                    serialize(&Value::BulkString(sha.into())).into()
+                }
+                "EXISTS" => format!(
+                    "*{}\r\n{}",
+                    command.1.len() - 1,
+                    command.1[1..]
+                        .iter()
+                        .map(|x| {
+                            let sha = extract_string(x).to_ascii_lowercase();
+                            format!(":{}\r\n", scripts.contains(&sha) as i64)
+                        })
+                        .collect::<String>()
+                )
+                .into(),
+                "FLUSH" => {
+                    scripts.clear();
+                    "+OK\r\n".into()
+                }
+                _ => format!(
+                    "-ERR unknown subcommand '{}'. Try SCRIPT HELP.\r\n",
+                    printable(&subcommand)
+                )
+                .into(),
+            }
+        }
+        "PFADD" => {
+            let key = extract_bytes(command.1.first().expect("ab"));
+            let mut writable = store.write().await;
+            let (mut registers, mut changed) = match writable.lookup_mut(&key).map(|x| x.string()) {
+                Some(Some(x)) => match hll_registers(x) {
+                    Some(registers) => (registers, false),
+                    None => return HLL_WRONGTYPE.into(),
+                },
+                Some(None) => return WRONGTYPE.into(),
+                None => (vec![0; HLL_REGISTERS], true),
+            };
+            for element in &command.1[1..] {
+                changed |= hll_add(&mut registers, &extract_bytes(element));
+            }
+            if changed {
+                let value = hll_value(&registers);
+                match writable.get_mut(&key) {
+                    Some(x) => {
+                        x.value = StoredData::String(value);
+                        x.raw = true;
+                    }
+                    None => {
+                        // Like SETRANGE, the registers are built in place as a raw string.
+                        let mut created = StoredValue::new(value, None);
+                        created.raw = true;
+                        writable.insert(key, created);
+                    }
+                }
+            }
+            format!(":{}\r\n", changed as i64).into()
+        }
+        "PFCOUNT" => {
+            let readable = store.read().await;
+            let mut registers = vec![0; HLL_REGISTERS];
+            for key in &command.1 {
+                let key = extract_bytes(key);
+                if let Some(x) = readable.lookup(&key) {
+                    match x.string().map(|x| hll_registers(x)) {
+                        Some(Some(other)) => hll_merge(&mut registers, &other),
+                        Some(None) => return HLL_WRONGTYPE.into(),
+                        None => return WRONGTYPE.into(),
+                    }
+                }
+            }
+            format!(":{}\r\n", hll_count(&registers)).into()
+        }
+        "PFMERGE" => {
+            let dest = extract_bytes(command.1.first().expect("ab"));
+            let mut writable = store.write().await;
+            let mut registers = vec![0; HLL_REGISTERS];
+            for key in &command.1 {
+                let key = extract_bytes(key);
+                if let Some(x) = writable.lookup(&key) {
+                    match x.string().map(|x| hll_registers(x)) {
+                        Some(Some(other)) => hll_merge(&mut registers, &other),
+                        Some(None) => return HLL_WRONGTYPE.into(),
+                        None => return WRONGTYPE.into(),
+                    }
+                }
+            }
+            let value = hll_value(&registers);
+            match writable.lookup_mut(&dest) {
+                Some(x) => {
+                    x.value = StoredData::String(value);
+                    x.raw = true;
+                }
+                None => {
+                    let mut created = StoredValue::new(value, None);
+                    created.raw = true;
+                    writable.insert(dest, created);
+                }
+            }
+            "+OK\r\n".into()
+        }
+        "HSET" => {
+            if command.1.len().is_multiple_of(2) {
+                return wrong_arity(&command.0);
+            }
+            let key = extract_bytes(&command.1[0]);
+            let mut writable = store.write().await;
+            let entry = writable.lookup_or_insert_with(key, || {
+                StoredValue::with_data(StoredData::Hash(HashMap::new()), None)
+            });
+            let hash = match &mut entry.value {
+                StoredData::Hash(hash) => hash,
+                _ => return WRONGTYPE.into(),
+            };
+            let created = command.1[1..]
+                .chunks(2)
+                .filter(|pair| {
+                    hash.insert(extract_bytes(&pair[0]), extract_bytes(&pair[1]))
+                        .is_none()
+                })
+                .count();
+            format!(":{}\r\n", created).into()
+        }
+        "HGET" | "HGETALL" => {
+            let key = extract_bytes(&command.1[0]);
+            let readable = store.read().await;
+            let hash = match readable.lookup(&key) {
+                Some(StoredValue {
+                    value: StoredData::Hash(hash),
+                    ..
+                }) => Some(hash),
+                Some(_) => return WRONGTYPE.into(),
+                None => None,
+            };
+            let reply = if name == "HGET" {
+                hash.and_then(|hash| hash.get(&extract_bytes(&command.1[1])))
+                    .map_or(Value::Null, |value| Value::BulkString(value.clone()))
+            } else {
+                Value::Array(
+                    hash.into_iter()
+                        .flatten()
+                        .flat_map(|(field, value)| {
+                            [
+                                Value::BulkString(field.clone()),
+                                Value::BulkString(value.clone()),
+                            ]
+                        })
+                        .collect(),
+                )
+            };
+            serialize(&reply).into()
+        }
+        "LPUSH" | "RPUSH" => {
+            let key = extract_bytes(&command.1[0]);
+            let mut writable = store.write().await;
+            let entry = writable.lookup_or_insert_with(key, || {
+                StoredValue::with_data(StoredData::List(VecDeque::new()), None)
+            });
+            let list = match &mut entry.value {
+                StoredData::List(list) => list,
+                _ => return WRONGTYPE.into(),
+            };
+            for element in &command.1[1..] {
+                if name == "LPUSH" {
+                    list.push_front(extract_bytes(element));
+                } else {
+                    list.push_back(extract_bytes(element));
+                }
+            }
+            format!(":{}\r\n", list.len()).into()
+        }
+        "LRANGE" | "LLEN" => {
+            let key = extract_bytes(&command.1[0]);
+            let readable = store.read().await;
+            let list = match readable.lookup(&key) {
+                Some(StoredValue {
+                    value: StoredData::List(list),
+                    ..
+                }) => Some(list),
+                Some(_) => return WRONGTYPE.into(),
+                None => None,
+            };
+            let len = list.map_or(0, |list| list.len()) as i64;
+            if name == "LLEN" {
+                return format!(":{}\r\n", len).into();
+            }
+            let mut bounds = [0; 2];
+            for (bound, arg) in bounds.iter_mut().zip(&command.1[1..]) {
+                *bound = match parse_i64(&extract_bytes(arg)) {
+                    Ok(i) if i < 0 => len + i,
+                    Ok(i) => i,
+                    Err(e) => return e.into(),
+                };
+            }
+            let start = bounds[0].max(0);
+            let stop = bounds[1].min(len - 1);
+            let items = match list {
+                Some(list) if start <= stop => list
+                    .range(start as usize..=stop as usize)
+                    .map(|x| Value::BulkString(x.clone()))
+                    .collect(),
+                _ => vec![],
+            };
+            serialize(&Value::Array(items)).into()
+        }
+        "DEBUG" => {
+            let subcommand = command.1.first().map(extract_string).unwrap_or_default();
+            match subcommand.to_ascii_uppercase().as_str() {
+                "HELP" => help_reply(
+                    "DEBUG",
+                    &[
+                        "OBJECT <key>",
+                        "    Show low level info about the key and associated value.",
+                        "SET-ACTIVE-EXPIRE <0|1>",
+                        "    Setting it to 0 disables expiring keys in background when they are not accessed.",
+                        "SLEEP <seconds>",
+                        "    Sleep the calling connection for <seconds>, other clients are still served. Decimals allowed.",
+                        "SLEEP-BLOCKING <seconds>",
+                        "    Like SLEEP, but hold every database's lock so other clients block too.",
+                    ],
+                ),
+                "OBJECT" | "SET-ACTIVE-EXPIRE" | "SLEEP" | "SLEEP-BLOCKING"
+                    if command.1.len() != 2 =>
+                {
+                    wrong_arity(&format!("debug|{}", subcommand))
+                }
+                "OBJECT" => {
+                    let key = extract_bytes(command.1.get(1).expect("ab"));
+                    let readable = store.read().await;
+                    match readable.get(&key).filter(|x| !x.is_expired()) {
+                        Some(x) => format!(
+                            "+Value at:0x0 refcount:{} encoding:{} serializedlength:{} lru:{} lru_seconds_idle:{} age:{}\r\n",
+                            x.refcount(),
+                            x.encoding(),
+                            x.serialized_length(),
+                            (x.last_access.load(Ordering::Relaxed) / 1000) & 0xFFFFFF,
+                            x.idle_time() / 1000,
+                            x.age() / 1000
+                        )
+                        .into(),
+                        None => "-ERR no such key\r\n".into(),
+                    }
+                }
+                "SET-ACTIVE-EXPIRE" => {
+                    let enabled = match parse_i64(&extract_bytes(command.1.get(1).expect("ab"))) {
+                        Ok(enabled) => enabled != 0,
+                        Err(e) => return e.into(),
+                    };
+                    server.active_expire.store(enabled, Ordering::Relaxed);
+                    "+OK\r\n".into()
+                }
+                "SLEEP" | "SLEEP-BLOCKING" => {
+                    // Negative, infinite and too large durations are refused rather than panicking.
+                    let duration = match parse_f64(&extract_bytes(command.1.get(1).expect("ab")))
+                        .map(Duration::try_from_secs_f64)
+                    {
+                        Ok(Ok(duration)) => duration,
+                        Ok(Err(_)) => return "-ERR value is out of range\r\n".into(),
+                        Err(e) => return e.into(),
+                    };
+                    if subcommand.eq_ignore_ascii_case("SLEEP-BLOCKING") {
+                        // Every database is locked, not just the selected one, so no
+                        // client can reach the keyspace until the sleep ends.
+                        let mut _locks = Vec::with_capacity(server.dbs.len());
+                        for db in &server.dbs {
+                            _locks.push(db.write().await);
+                        }
+                        tokio::time::sleep(duration).await;
+                    } else {
+                        tokio::time::sleep(duration).await;
+                    }
+                    "+OK\r\n".into()
+                }
+                _ => format!(
+                    "-ERR unknown subcommand '{}'. Try DEBUG HELP.\r\n",
+                    printable(&subcommand)
+                )
+                .into(),
+            }
+        }
+        "CLIENT" => {
+            let subcommand = command.1.first().map(extract_string).unwrap_or_default();
+            match subcommand.to_ascii_uppercase().as_str() {
+                "HELP" => help_reply(
+                    "CLIENT",
+                    &[
+                        "ID",
+                        "    Return the ID of the current connection.",
+                        "KILL <ip:port>",
+                        "    Kill connection made from <ip:port>.",
+                        "KILL <option> <value> [<option> <value> [...]]",
+                        "    Kill connections. Options are:",
+                        "    * ADDR <ip:port>",
+                        "      Kill connection made from <ip:port>",
+                        "    * ID <client-id>",
+                        "      Kill connections by client id.",
+                        "    * SKIPME (YES|NO)",
+                        "      Skip killing current connection (default: yes).",
+                        "PAUSE <timeout> [WRITE|ALL]",
+                        "    Suspend all, or just write, clients for <timeout> milliseconds.",
+                        "UNPAUSE",
+                        "    Stop the current client pause, resuming traffic.",
+                    ],
+                ),
+                "PAUSE" => {
+                    let timeout = match command.1.get(1).map(|x| parse_i64(&extract_bytes(x))) {
+                        Some(Ok(timeout)) if timeout >= 0 => timeout as u128,
+                        _ => return "-ERR timeout is not an integer or out of range\r\n".into(),
+                    };
+                    let writes_only = match command.1.get(2).map(extract_string) {
+                        None => false,
+                        Some(mode) if mode.eq_ignore_ascii_case("ALL") => false,
+                        Some(mode) if mode.eq_ignore_ascii_case("WRITE") => true,
+                        Some(_) => return "-ERR syntax error\r\n".into(),
+                    };
+                    *server.pause.lock().expect("pause") =
+                        Some((get_time() + timeout, writes_only));
+                    "+OK\r\n".into()
+                }
+                "UNPAUSE" => {
+                    *server.pause.lock().expect("pause") = None;
+                    "+OK\r\n".into()
+                }
+                "ID" => format!(":{}\r\n", client.id).into(),
+                // The old form CLIENT KILL addr, which may kill the caller itself.
+                "KILL" if command.1.len() == 2 => {
+                    let addr = extract_string(&command.1[1]);
+                    match kill_clients(&server, |_, handle| handle.addr == addr) {
+                        0 => "-ERR No such client\r\n".into(),
+                        _ => "+OK\r\n".into(),
+                    }
+                }
+                "KILL" if command.1.len() == 1 => "-ERR syntax error\r\n".into(),
+                "KILL" => {
+                    let mut id = None;
+                    let mut addr = None;
+                    let mut skip_me = true;
+                    for pair in command.1[1..].chunks(2) {
+                        let value = match pair.get(1) {
+                            Some(value) => extract_string(value),
+                            None => return "-ERR syntax error\r\n".into(),
+                        };
+                        match extract_string(&pair[0]).to_ascii_uppercase().as_str() {
+                            "ID" => match parse_i64(value.as_bytes()) {
+                                Ok(x) if x > 0 => id = Some(x as u64),
+                                _ => return "-ERR client-id should be greater than 0\r\n".into(),
+                            },
+                            "ADDR" => addr = Some(value),
+                            "SKIPME" if value.eq_ignore_ascii_case("YES") => skip_me = true,
+                            "SKIPME" if value.eq_ignore_ascii_case("NO") => skip_me = false,
+                            _ => return "-ERR syntax error\r\n".into(),
+                        }
+                    }
+                    let killed = kill_clients(&server, |x, handle| {
+                        id.is_none_or(|id| id == x)
+                            && addr.as_ref().is_none_or(|addr| *addr == handle.addr)
+                            && !(skip_me && x == client.id)
+                    });
+                    format!(":{}\r\n", killed).into()
+                }
+                _ => format!(
+                    "-ERR unknown subcommand '{}'. Try CLIENT HELP.\r\n",
+                    printable(&subcommand)
+                )
+                .into(),
+            }
+        }
+        "LOLWUT" => {
+            match command
+                .1
+                .iter()
+                .map(extract_bytes)
+                .collect::<Vec<_>>()
+                .as_slice()
+            {
+                [] => {}
+                [option, version] if option.eq_ignore_ascii_case(b"VERSION") => {
+                    if let Err(e) = parse_i64(version) {
+                        return e.into();
+                    }
+                }
+                _ => return "-ERR syntax error\r\n".into(),
+            }
+            // There is no art, only the version line every LOLWUT output ends with.
+            serialize(&Value::BulkString(
+                format!("Redis ver. {}\n", VERSION).into(),
+            ))
+            .into()
+        }
+        "INFO" => {
+            let section = command
+                .1
+                .first()
+                .map(|x| extract_string(x).to_ascii_lowercase());
+            let info = match section.as_deref() {
+                None | Some("server") | Some("default") | Some("all") | Some("everything") => {
+                    format!("# Server\r\nredis_version:{}\r\n", VERSION)
+                }
+                Some(_) => String::new(),
+            };
+            format!("${}\r\n{}\r\n", info.len(), info).into()
+        }
+        "COMMAND" => {
+            let subcommand = command.1.first().map(extract_string).unwrap_or_default();
+            match subcommand.to_ascii_uppercase().as_str() {
+                "HELP" => help_reply(
+                    "COMMAND",
+                    &[
+                        "(no subcommand)",
+                        "    Return details about all commands.",
+                        "COUNT",
+                        "    Return the total number of commands in this server.",
+                        "DOCS [<command-name> ...]",
+                        "    Return documentation details about multiple commands.",
+                        "LIST [FILTERBY (MODULE <module-name>|ACLCAT <category>|PATTERN <pattern>)]",
+                        "    Return a list of all commands in this server.",
+                    ],
+                ),
+                "COUNT" => format!(":{}\r\n", server.config.visible_commands().count()).into(),
+                "LIST" => {
+                    let filter: Vec<String> = command.1[1..].iter().map(extract_string).collect();
+                    let pattern = match filter.as_slice() {
+                        [] => Some("*".into()),
+                        [filterby, kind, value] if filterby.eq_ignore_ascii_case("FILTERBY") => {
+                            match kind.to_ascii_uppercase().as_str() {
+                                "PATTERN" => Some(value.to_string()),
+                                "MODULE" | "ACLCAT" => None,
+                                _ => return "-ERR syntax error\r\n".into(),
+                            }
+                        }
+                        _ => return "-ERR syntax error\r\n".into(),
+                    };
+                    let names = server
+                        .config
+                        .visible_commands()
+                        .map(|(name, _)| name.to_ascii_lowercase())
+                        .filter(|name| {
+                            pattern
+                                .as_ref()
+                                .is_some_and(|x| glob_match(x.as_bytes(), name.as_bytes(), true))
+                        })
+                        .map(|name| Value::BulkString(name.into()))
+                        .collect();
+                    serialize(&Value::Array(names)).into()
+                }
+                "DOCS" => {
+                    let names: Vec<String> = command.1[1..]
+                        .iter()
+                        .map(|x| extract_string(x).to_ascii_uppercase())
+                        .collect();
+                    let mut docs = Vec::new();
+                    for (name, spec) in server.config.visible_commands() {
+                        if names.is_empty() || names.contains(&name) {
+                            docs.push(Value::BulkString(name.to_ascii_lowercase().into()));
+                            docs.push(command_docs(spec));
+                        }
+                    }
+                    serialize(&Value::Array(docs)).into()
+                }
+                _ => format!(
+                    "-ERR unknown subcommand '{}'. Try COMMAND HELP.\r\n",
+                    printable(&subcommand)
+                )
+                .into(),
+            }
+        }
+        _ => format!("-ERR unknown command '{}'\r\n", printable(&command.0)).into(),
+    }
+}
+
+// Runs every complete command in the buffer and removes it, leaving a
+// trailing partial frame in place until more bytes arrive.
+async fn handle_buffer(
+    buffer: &mut Vec<u8>,
+    server: &Arc<Server>,
+    client: &mut Client,
+) -> (Vec<u8>, bool) {
+    // A bulk string arriving in small reads is only parsed again once all of it is here.
+    if buffer.len() < client.needed {
+        return (Vec::new(), true);
+    }
+    let data = Bytes::from(std::mem::take(buffer));
+    let mut parser = Parser::from_bytes(
+        data.clone(),
+        DEFAULT_MAX_MULTIBULK_LEN,
+        server.config.proto_max_bulk_len,
+    );
+    let mut output = Vec::new();
+    loop {
+        if parser.pos >= data.len() {
+            client.needed = 0;
+            return (output, true);
+        }
+        let start = parser.pos;
+        let value = match parser.parse_value() {
+            Ok(value) => value,
+            Err(ParseError::Incomplete) => {
+                buffer.extend_from_slice(&data[start..]);
+                client.needed = parser.needed.saturating_sub(start);
+                return (output, true);
+            }
+            Err(e) => {
+                output.extend_from_slice(format!("-ERR {}\r\n", e).as_bytes());
+                return (output, false);
+            }
+        };
+        let is_command = match &value {
+            Value::Array(items) => items
+                .iter()
+                .all(|x| matches!(x, Value::SimpleString(_) | Value::BulkString(_))),
+            _ => false,
+        };
+        if !is_command {
+            output.extend_from_slice(format!("-ERR {}\r\n", ParseError::InvalidCommand).as_bytes());
+            return (output, false);
+        }
+        if value != Value::Array(vec![]) {
+            let command: (String, Vec<Value>) = get_command(value);
+            let res = handle_command(command, Arc::clone(server), client).await;
+            output.extend_from_slice(&res);
+            client.pending_output += res.len();
+        }
+        // Past the limit nothing more is run, the replies so far are still sent.
+        let limit = server.config.maxmemory_clients;
+        if limit > 0 && client.pending_output > limit {
+            return (output, false);
+        }
+    }
+}
+
+async fn handle_client(server: Arc<Server>, mut stream: TcpStream) {
+    let mut buffer = Vec::new();
+    let mut client = Client {
+        id: server.next_client_id.fetch_add(1, Ordering::Relaxed),
+        ..Client::default()
+    };
+    let kill = Arc::new(Notify::new());
+    let addr = stream
+        .peer_addr()
+        .map_or_else(|_| String::new(), |addr| addr.to_string());
+    server.clients.lock().expect("clients").insert(
+        client.id,
+        ClientHandle {
+            addr,
+            kill: Arc::clone(&kill),
+        },
+    );
+    let (mut reader, mut writer) = stream.split();
+    let limited = server.config.maxmemory_clients > 0;
+    let mut pending = Vec::new();
+    let mut sent = 0;
+    let mut keep_alive = true;
+    while keep_alive || sent < pending.len() {
+        // Without a limit a client that stops reading replies is not read from either.
+        let readable = keep_alive && (limited || sent == pending.len());
+        tokio::select! {
+            n = read(&mut reader, &mut buffer), if readable => {
+                if n == 0 {
+                    keep_alive = false;
+                    continue;
+                }
+                let (output, alive) = handle_buffer(&mut buffer, &server, &mut client).await;
+                pending.extend_from_slice(&output);
+                keep_alive = alive;
+            }
+            n = writer.write(&pending[sent..]), if sent < pending.len() => match n {
+                Ok(n) if n > 0 => sent += n,
+                _ => break,
+            },
+            _ = kill.notified() => break,
+        }
+        if sent == pending.len() {
+            pending.clear();
+            sent = 0;
+        }
+        client.pending_output = pending.len() - sent;
+    }
+    server.clients.lock().expect("clients").remove(&client.id);
+}
+
+// Closes every connection accepted by the filter and returns how many there were.
+fn kill_clients(server: &Server, filter: impl Fn(u64, &ClientHandle) -> bool) -> usize {
+    let clients = server.clients.lock().expect("clients");
+    let mut killed = 0;
+    for (id, handle) in clients.iter() {
+        if filter(*id, handle) {
+            handle.kill.notify_one();
+            killed += 1;
+        }
+    }
+    killed
+}
+
+pub struct Config {
+    // Number of tokio worker threads, defaults to the number of CPUs.
+    io_threads: usize,
+    // Number of logical databases selectable with SELECT.
+    databases: usize,
+    // Maximum size in bytes of a connection's pending replies, 0 for no limit.
+    maxmemory_clients: usize,
+    // Largest bulk string a client may send, in bytes.
+    proto_max_bulk_len: usize,
+    // Connections accepted per second, 0 for no limit.
+    max_accepts_per_sec: u32,
+    // Original command name to its new name, an empty new name disables the command.
+    renamed_commands: HashMap<String, String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            io_threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            databases: DATABASES,
+            maxmemory_clients: 0,
+            proto_max_bulk_len: DEFAULT_PROTO_MAX_BULK_LEN,
+            max_accepts_per_sec: 0,
+            renamed_commands: HashMap::new(),
+        }
+    }
+}
+
+impl Config {
+    fn from_args(mut args: impl Iterator<Item = String>) -> Result<Config, String> {
+        let mut config = Config::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--io-threads" => {
+                    let value = args.next().ok_or("--io-threads requires a value")?;
+                    config.io_threads = match value.parse::<usize>() {
+                        Ok(n) if n > 0 => n,
+                        _ => return Err(format!("invalid --io-threads value '{}'", value)),
+                    };
+                }
+                "--databases" => {
+                    let value = args.next().ok_or("--databases requires a value")?;
+                    config.databases = match value.parse::<usize>() {
+                        Ok(n) if n > 0 => n,
+                        _ => return Err(format!("invalid --databases value '{}'", value)),
+                    };
+                }
+                "--maxmemory-clients" => {
+                    let value = args.next().ok_or("--maxmemory-clients requires a value")?;
+                    config.maxmemory_clients = value
+                        .parse::<usize>()
+                        .map_err(|_| format!("invalid --maxmemory-clients value '{}'", value))?;
+                }
+                "--proto-max-bulk-len" => {
+                    let value = args.next().ok_or("--proto-max-bulk-len requires a value")?;
+                    config.proto_max_bulk_len = value
+                        .parse::<usize>()
+                        .map_err(|_| format!("invalid --proto-max-bulk-len value '{}'", value))?;
+                }
+                "--max-accepts-per-sec" => {
+                    let value = args
+                        .next()
+                        .ok_or("--max-accepts-per-sec requires a value")?;
+                    config.max_accepts_per_sec = value
+                        .parse::<u32>()
+                        .map_err(|_| format!("invalid --max-accepts-per-sec value '{}'", value))?;
+                }
+                "--rename-command" => {
+                    let name = args.next().ok_or("--rename-command requires a name")?;
+                    let new_name = args.next().ok_or("--rename-command requires a new name")?;
+                    config
+                        .renamed_commands
+                        .insert(name.to_ascii_uppercase(), new_name.to_ascii_uppercase());
+                }
+                _ => return Err(format!("unknown option '{}'", arg)),
+            }
+        }
+        Ok(config)
+    }
+
+    fn resolve_command(&self, name: &str) -> Option<String> {
+        let name = name.to_ascii_uppercase();
+        if let Some((original, _)) = self
+            .renamed_commands
+            .iter()
+            .find(|(_, new_name)| !new_name.is_empty() && **new_name == name)
+        {
+            return Some(original.clone());
+        }
+        if self.renamed_commands.contains_key(&name) {
+            return None;
+        }
+        Some(name)
+    }
+
+    // The name a registered command is reachable under, None if it was disabled.
+    fn visible_name(&self, original: &str) -> Option<String> {
+        match self.renamed_commands.get(original) {
+            Some(new_name) if new_name.is_empty() => None,
+            Some(new_name) => Some(new_name.clone()),
+            None => Some(original.to_string()),
+        }
+    }
+
+    // Registered commands that are still reachable, with the name clients use for them.
+    fn visible_commands(&self) -> impl Iterator<Item = (String, &'static CommandSpec)> + '_ {
+        COMMANDS
+            .iter()
+            .filter_map(|spec| Some((self.visible_name(spec.name)?, spec)))
+    }
+}
+
+pub struct Server {
+    dbs: Vec<RwLock<Db>>,
+    config: Config,
+    scripts: Mutex<HashSet<String>>,
+    active_expire: AtomicBool,
+    // Set by CLIENT PAUSE: the time in ms to pause until, and whether only writes are paused.
+    pause: Mutex<Option<(u128, bool)>>,
+    next_client_id: AtomicU64,
+    // Open connections by client id.
+    clients: Mutex<HashMap<u64, ClientHandle>>,
+}
+
+impl Server {
+    fn new(config: Config) -> Server {
+        Server {
+            dbs: (0..config.databases)
+                .map(|_| RwLock::new(Db::default()))
+                .collect(),
+            config,
+            scripts: Mutex::new(HashSet::new()),
+            active_expire: AtomicBool::new(true),
+            pause: Mutex::new(None),
+            next_client_id: AtomicU64::new(1),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+fn build_runtime(io_threads: usize) -> io::Result<tokio::runtime::Runtime> {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(io_threads)
+        .enable_all()
+        .build()
+}
+
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: std::time::Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> TokenBucket {
+        TokenBucket {
+            rate,
+            tokens: rate,
+            last: std::time::Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = std::time::Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+    }
+
+    async fn acquire(&mut self) {
+        self.refill();
+        if self.tokens < 1.0 {
+            tokio::time::sleep(Duration::from_secs_f64((1.0 - self.tokens) / self.rate)).await;
+            self.refill();
+        }
+        self.tokens -= 1.0;
+    }
+}
+
+async fn run_server(listener: TcpListener, server: Arc<Server>) {
+    let rate = server.config.max_accepts_per_sec;
+    let mut bucket = (rate > 0).then(|| TokenBucket::new(rate as f64));
+    loop {
+        if let Some(bucket) = &mut bucket {
+            bucket.acquire().await;
+        }
+        match listener.accept().await {
+            Ok((_stream, _)) => {
+                tokio::spawn(handle_client(Arc::clone(&server), _stream));
+                println!("accepted new connection");
+            }
+            Err(e) => {
+                println!("error: {}", e);
+            }
+        }
+    }
+}
+
+async fn serve(config: Config) -> io::Result<()> {
+    let server = Arc::new(Server::new(config));
+    let listener = TcpListener::bind("127.0.0.1:6379").await?;
+    tokio::spawn(active_expire(Arc::clone(&server)));
+    run_server(listener, server).await;
+    Ok(())
+}
+
+fn main() -> io::Result<()> {
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    println!(
+        "Starting redis-rust {} with {} commands and {} io threads",
+        VERSION,
+        config.visible_commands().count(),
+        config.io_threads
+    );
+    build_runtime(config.io_threads)?.block_on(serve(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_parse_string() {
+        let data = b"+ABC\r\n";
+        let mut p = Parser::new(data);
+        assert_eq!(
+            p.parse_value(),
+            Ok(Value::SimpleString(String::from("ABC")))
+        );
+    }
+    #[test]
+    fn test_parse_integer() {
+        let mut p = Parser::new(b":1000\r\n");
+        assert_eq!(p.parse_value(), Ok(Value::Integer(1000)));
+    }
+    #[test]
+    fn test_parse_negative_integer() {
+        let mut p = Parser::new(b":-42\r\n");
+        assert_eq!(p.parse_value(), Ok(Value::Integer(-42)));
+    }
+    #[test]
+    fn test_parse_zero_integer() {
+        let mut p = Parser::new(b":0\r\n");
+        assert_eq!(p.parse_value(), Ok(Value::Integer(0)));
+    }
+    #[test]
+    fn test_parse_invalid_integer() {
+        let mut p = Parser::new(b":12a\r\n");
+        assert_eq!(p.parse_value(), Err(ParseError::InvalidInteger));
+    }
+    #[test]
+    fn test_parse_error() {
+        let mut p = Parser::new(b"-ERR unknown command 'FOO'\r\n");
+        assert_eq!(
+            p.parse_value(),
+            Ok(Value::Error(String::from("ERR unknown command 'FOO'")))
+        );
+    }
+    #[test]
+    fn test_parse_empty_string() {
+        let data = b"+\r\n";
+        let mut p = Parser::new(data);
+        assert_eq!(p.parse_value(), Ok(Value::SimpleString(String::from(""))));
+    }
+    #[test]
+    fn test_parse_bulk_string() {
+        let data = b"$6\r\nabcdef\r\n";
+        let mut p = Parser::new(data);
+        assert_eq!(
+            p.parse_value(),
+            Ok(Value::BulkString(Bytes::from("abcdef")))
+        );
+    }
+    #[test]
+    fn test_parse_array() {
+        let data = b"*2\r\n+AB\r\n+CD\r\n";
+        let mut p = Parser::new(data);
+        assert_eq!(
+            p.parse_value(),
+            Ok(Value::Array(vec![
+                Value::SimpleString(String::from("AB")),
+                Value::SimpleString(String::from("CD"))
+            ]))
+        );
+    }
+    #[test]
+    fn test_parse_echo_command() {
+        let data = b"*2\r\n$4\r\nECHO\r\n$3\r\nhey\r\n";
+        let mut p = Parser::new(data);
+        assert_eq!(
+            p.parse_value(),
+            Ok(Value::Array(vec![
+                Value::BulkString(Bytes::from("ECHO")),
+                Value::BulkString(Bytes::from("hey"))
+            ]))
+        );
+    }
+    #[test]
+    fn test_parse_echo_command_no_args() {
+        let data = b"*1\r\n$4\r\nECHO\r\n";
+        let mut p = Parser::new(data);
+        assert_eq!(
+            p.parse_value(),
+            Ok(Value::Array(vec![Value::BulkString(Bytes::from("ECHO"))]))
+        );
+    }
+    #[test]
+    fn test_parse_get_command() {
+        let data = b"*2\r\n$3\r\nGET\r\n$3\r\nKEY\r\n";
+        let mut p = Parser::new(data);
+        assert_eq!(
+            p.parse_value(),
+            Ok(Value::Array(vec![
+                Value::BulkString(Bytes::from("GET")),
+                Value::BulkString(Bytes::from("KEY"))
+            ]))
+        );
+    }
+    #[test]
+    fn test_get_command_get() {
+        let data = Value::Array(vec![
+            Value::BulkString(Bytes::from("GET")),
+            Value::BulkString(Bytes::from("hey")),
+        ]);
+        assert_eq!(
+            get_command(data),
+            (
+                "GET".to_string(),
+                vec![Value::BulkString(Bytes::from("hey"))]
+            )
+        );
+    }
+    #[test]
+    fn test_get_command_set() {
+        let data = Value::Array(vec![
+            Value::BulkString(Bytes::from("SET")),
+            Value::BulkString(Bytes::from("hey")),
+            Value::BulkString(Bytes::from("value")),
+        ]);
+        assert_eq!(
+            get_command(data),
+            (
+                "SET".to_string(),
+                vec![
+                    Value::BulkString(Bytes::from("hey")),
+                    Value::BulkString(Bytes::from("value"))
+                ]
+            )
+        );
+    }
+    #[test]
+    fn test_parse_command() {
+        let data = Value::Array(vec![
+            Value::BulkString(Bytes::from("ECHO")),
+            Value::BulkString(Bytes::from("hey")),
+        ]);
+        assert_eq!(
+            get_command(data),
+            (
+                "ECHO".to_string(),
+                vec![Value::BulkString(Bytes::from("hey"))]
+            )
+        );
+    }
+    async fn run(command: (String, Vec<Value>), server: Arc<Server>) -> Bytes {
+        handle_command(command, server, &mut Client::default()).await
+    }
+    fn text(reply: Bytes) -> String {
+        String::from_utf8(reply.to_vec()).expect("utf-8 reply")
+    }
+    fn command(args: &[&str]) -> (String, Vec<Value>) {
+        (
+            args[0].to_string(),
+            args[1..]
+                .iter()
+                .map(|x| Value::BulkString(x.to_string().into()))
+                .collect(),
+        )
+    }
+    fn binary_command(name: &str, args: &[&[u8]]) -> (String, Vec<Value>) {
+        (
+            name.to_string(),
+            args.iter()
+                .map(|x| Value::BulkString(Bytes::copy_from_slice(x)))
+                .collect(),
+        )
+    }
+    async fn server_with_ttl_key() -> Arc<Server> {
+        let server = Arc::new(Server::new(Config::default()));
+        run(
+            command(&["SET", "key", "old", "PX", "100000"]),
+            Arc::clone(&server),
+        )
+        .await;
+        server
+    }
+    #[tokio::test]
+    async fn test_set_clears_previous_ttl() {
+        let server = server_with_ttl_key().await;
+        assert_eq!(
+            run(command(&["SET", "key", "new"]), Arc::clone(&server)).await,
+            "+OK\r\n"
+        );
+        let readable = server.dbs[0].read().await;
+        assert_eq!(
+            readable[&b"key"[..]].value,
+            StoredData::String(Bytes::from("new"))
+        );
+        assert_eq!(readable[&b"key"[..]].expiry, None);
+    }
+    #[tokio::test]
+    async fn test_set_keepttl_preserves_previous_ttl() {
+        let server = server_with_ttl_key().await;
+        let expiry = server.dbs[0].read().await[&b"key"[..]].expiry;
+        assert!(expiry.is_some());
+        assert_eq!(
+            run(
+                command(&["SET", "key", "new", "KEEPTTL"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "+OK\r\n"
+        );
+        let readable = server.dbs[0].read().await;
+        assert_eq!(
+            readable[&b"key"[..]].value,
+            StoredData::String(Bytes::from("new"))
+        );
+        assert_eq!(readable[&b"key"[..]].expiry, expiry);
+    }
+    #[tokio::test]
+    async fn test_set_ex_replaces_previous_ttl() {
+        let server = server_with_ttl_key().await;
+        let before = get_time();
+        assert_eq!(
+            run(
+                command(&["SET", "key", "new", "EX", "1000"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "+OK\r\n"
+        );
+        let expiry = server.dbs[0].read().await[&b"key"[..]]
+            .expiry
+            .expect("expiry");
+        assert!(expiry >= before + 1_000_000);
+        assert!(expiry <= get_time() + 1_000_000);
+    }
+    #[tokio::test]
+    async fn test_set_px_replaces_previous_ttl() {
+        let server = server_with_ttl_key().await;
+        let before = get_time();
+        run(
+            command(&["SET", "key", "new", "px", "500"]),
+            Arc::clone(&server),
+        )
+        .await;
+        let expiry = server.dbs[0].read().await[&b"key"[..]]
+            .expiry
+            .expect("expiry");
+        assert!(expiry >= before + 500);
+        assert!(expiry <= get_time() + 500);
+    }
+    #[tokio::test]
+    async fn test_set_rejects_conflicting_expiry_options() {
+        let server = server_with_ttl_key().await;
+        assert_eq!(
+            run(
+                command(&["SET", "key", "new", "EX", "10", "KEEPTTL"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "-ERR syntax error\r\n"
+        );
+        assert_eq!(
+            server.dbs[0].read().await[&b"key"[..]].value,
+            StoredData::String(Bytes::from("old"))
+        );
+    }
+    #[tokio::test]
+    async fn test_command_count_matches_registry() {
+        let server = Arc::new(Server::new(Config::default()));
+        assert_eq!(
+            run(command(&["COMMAND", "COUNT"]), server).await,
+            format!(":{}\r\n", COMMANDS.len())
+        );
+    }
+    #[tokio::test]
+    async fn test_hello_reports_version() {
+        let server = Arc::new(Server::new(Config::default()));
+        let reply = run(command(&["HELLO", "2"]), Arc::clone(&server)).await;
+        let items = match Parser::new(&reply).parse_value() {
+            Ok(Value::Array(items)) => items,
+            other => panic!("unexpected reply {:?}", other),
+        };
+        assert_eq!(items.len(), 14);
+        assert_eq!(items[2], Value::BulkString(Bytes::from("version")));
+        assert_eq!(items[3], Value::BulkString(Bytes::from(VERSION)));
+        assert_eq!(items[5], Value::Integer(2));
+        assert_eq!(run(command(&["HELLO"]), Arc::clone(&server)).await, reply);
+        for protover in ["1", "3", "4"] {
+            assert_eq!(
+                run(command(&["HELLO", protover]), Arc::clone(&server)).await,
+                "-NOPROTO unsupported protocol version\r\n"
+            );
+        }
+        assert_eq!(
+            run(command(&["HELLO", "x"]), Arc::clone(&server)).await,
+            "-ERR Protocol version is not an integer or out of range\r\n"
+        );
+        assert_eq!(
+            run(
+                command(&["HELLO", "2", "SETNAME", "a"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "-ERR Syntax error in HELLO option 'SETNAME'\r\n"
+        );
+        assert_eq!(
+            run(command(&["HELLO", "2", "x\r\n+OK"]), Arc::clone(&server)).await,
+            "-ERR Syntax error in HELLO option 'x  +OK'\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_lolwut_reports_version() {
+        let server = Arc::new(Server::new(Config::default()));
+        let expected = format!("Redis ver. {}\n", VERSION);
+        let expected = format!("${}\r\n{}\r\n", expected.len(), expected);
+        assert_eq!(
+            run(command(&["LOLWUT"]), Arc::clone(&server)).await,
+            expected
+        );
+        assert_eq!(
+            run(command(&["LOLWUT", "VERSION", "5"]), Arc::clone(&server)).await,
+            expected
+        );
+        assert_eq!(
+            run(command(&["LOLWUT", "VERSION", "x"]), Arc::clone(&server)).await,
+            "-ERR value is not an integer or out of range\r\n"
+        );
+        assert_eq!(
+            run(command(&["LOLWUT", "5"]), Arc::clone(&server)).await,
+            "-ERR syntax error\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_info_reports_version() {
+        let server = Arc::new(Server::new(Config::default()));
+        let info = text(run(command(&["INFO", "server"]), server).await);
+        assert!(info.contains(&format!("redis_version:{}\r\n", VERSION)));
+    }
+    #[test]
+    fn test_parse_multibulk_length_over_limit() {
+        let data = b"*999999999\r\n";
+        let mut p = Parser::new(data);
+        assert_eq!(p.parse_value(), Err(ParseError::InvalidMultibulkLength));
+    }
+    #[test]
+    fn test_parse_multibulk_length_configured_limit() {
+        let data = b"*3\r\n+A\r\n+B\r\n+C\r\n";
+        assert_eq!(
+            Parser::with_max_multibulk_len(data, 2).parse_value(),
+            Err(ParseError::InvalidMultibulkLength)
+        );
+        assert!(Parser::with_max_multibulk_len(data, 3)
+            .parse_value()
+            .is_ok());
+    }
+    #[test]
+    fn test_parse_multibulk_length_not_a_number() {
+        let data = b"*abc\r\n";
+        let mut p = Parser::new(data);
+        assert_eq!(p.parse_value(), Err(ParseError::InvalidMultibulkLength));
+    }
+    #[test]
+    fn test_parse_i64() {
+        assert_eq!(parse_i64(b"5"), Ok(5));
+        assert_eq!(parse_i64(b"-42"), Ok(-42));
+        assert_eq!(parse_i64(b"0"), Ok(0));
+        assert_eq!(parse_i64(b"9223372036854775807"), Ok(i64::MAX));
+        assert_eq!(parse_i64(b"-9223372036854775808"), Ok(i64::MIN));
+    }
+    #[test]
+    fn test_parse_i64_rejects_malformed_input() {
+        let error = Err("-ERR value is not an integer or out of range\r\n".to_string());
+        for input in [
+            &b" 5"[..],
+            b"5 ",
+            b"",
+            b"3.0e2",
+            b"+5",
+            b"05",
+            b"-0",
+            b"-",
+            b"9223372036854775808",
+        ] {
+            assert_eq!(parse_i64(input), error, "{:?}", input);
+        }
+    }
+    #[test]
+    fn test_parse_f64() {
+        assert_eq!(parse_f64(b"3.0e2"), Ok(300.0));
+        assert_eq!(parse_f64(b"-1.5"), Ok(-1.5));
+        assert_eq!(parse_f64(b"inf"), Ok(f64::INFINITY));
+        let error = Err("-ERR value is not a valid float\r\n".to_string());
+        for input in [&b" 5"[..], b"5 ", b"", b"nan", b"1.0x"] {
+            assert_eq!(parse_f64(input), error, "{:?}", input);
+        }
+    }
+    #[tokio::test]
+    async fn test_set_rejects_malformed_expiry() {
+        let server = Arc::new(Server::new(Config::default()));
+        assert_eq!(
+            run(command(&["SET", "key", "value", "EX", " 5"]), server).await,
+            "-ERR value is not an integer or out of range\r\n"
+        );
+    }
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>()
+            .into_iter()
+    }
+    #[test]
+    fn test_config_io_threads() {
+        let config = Config::from_args(args(&[])).expect("config");
+        assert!(config.io_threads >= 1);
+        let config = Config::from_args(args(&["--io-threads", "3"])).expect("config");
+        assert_eq!(config.io_threads, 3);
+        assert!(Config::from_args(args(&["--io-threads", "0"])).is_err());
+        assert!(Config::from_args(args(&["--io-threads"])).is_err());
+        assert!(Config::from_args(args(&["--unknown"])).is_err());
+        let config = Config::from_args(args(&["--maxmemory-clients", "4096"])).expect("config");
+        assert_eq!(config.maxmemory_clients, 4096);
+        assert_eq!(Config::default().maxmemory_clients, 0);
+    }
+    #[test]
+    fn test_runtime_with_io_threads_serves_ping() {
+        let runtime = build_runtime(2).expect("runtime");
+        runtime.block_on(async {
+            let mut client = TcpStream::connect(spawn_server().await)
+                .await
+                .expect("connect");
+            client
+                .write_all(b"*1\r\n$4\r\nPING\r\n")
+                .await
+                .expect("write");
+            let mut reply = [0; 7];
+            client.read_exact(&mut reply).await.expect("read");
+            assert_eq!(&reply, b"+PONG\r\n");
+        });
+    }
+    fn add_keys(store: &mut Db, prefix: &str, keys: usize, expiry: Option<u128>) {
+        for i in 0..keys {
+            store.insert(
+                format!("{}:{}", prefix, i).into(),
+                StoredValue::new(Bytes::from("value"), expiry),
+            );
+        }
+    }
+    #[test]
+    fn test_active_expire_cycle_is_bounded() {
+        let mut store = Db::default();
+        add_keys(&mut store, "key", 1000, Some(0));
+        let mut cursor = 0;
+        assert_eq!(
+            active_expire_cycle(&mut store, &mut cursor),
+            (ACTIVE_EXPIRE_SAMPLE, ACTIVE_EXPIRE_SAMPLE)
+        );
+        assert_eq!(store.entries.len(), 1000 - ACTIVE_EXPIRE_SAMPLE);
+        let mut cycles = 1;
+        while !store.entries.is_empty() {
+            let (sampled, expired) = active_expire_cycle(&mut store, &mut cursor);
+            assert!(sampled <= ACTIVE_EXPIRE_SAMPLE);
+            assert_eq!(sampled, expired);
+            cycles += 1;
+        }
+        assert_eq!(cycles, 1000 / ACTIVE_EXPIRE_SAMPLE);
+    }
+    #[test]
+    fn test_active_expire_cycle_skips_live_keys() {
+        let mut store = Db::default();
+        add_keys(&mut store, "live", 100, Some(get_time() + 100_000));
+        add_keys(&mut store, "old", 1000, Some(0));
+        add_keys(&mut store, "persistent", 1000, None);
+        let mut cursor = 0;
+        for _ in 0..1000 {
+            let (sampled, _) = active_expire_cycle(&mut store, &mut cursor);
+            assert!(sampled <= ACTIVE_EXPIRE_SAMPLE);
+        }
+        assert_eq!(store.entries.len(), 1100);
+        assert!(store.values().all(|x| !x.is_expired()));
+    }
+    #[test]
+    fn test_active_expire_cycle_does_not_visit_persistent_keys() {
+        let mut store = Db::default();
+        add_keys(&mut store, "persistent", 100_000, None);
+        add_keys(&mut store, "old", 30, Some(0));
+        // Only the 30 volatile keys are ever looked at, however large the keyspace.
+        assert_eq!(store.volatile.len(), 30);
+        let mut cursor = 0;
+        assert_eq!(active_expire_cycle(&mut store, &mut cursor), (20, 20));
+        assert_eq!(active_expire_cycle(&mut store, &mut cursor), (10, 10));
+        assert_eq!(active_expire_cycle(&mut store, &mut cursor), (0, 0));
+        assert_eq!(store.entries.len(), 100_000);
+    }
+    #[tokio::test]
+    async fn test_volatile_index_follows_ttl_changes() {
+        let server = Arc::new(Server::new(Config::default()));
+        let volatile = |server: &Arc<Server>| {
+            let server = Arc::clone(server);
+            async move {
+                let mut keys = server.dbs[0].read().await.volatile.clone();
+                keys.sort();
+                keys
+            }
+        };
+        run(
+            command(&["SET", "a", "1", "EX", "100"]),
+            Arc::clone(&server),
+        )
+        .await;
+        run(command(&["SET", "b", "1"]), Arc::clone(&server)).await;
+        run(command(&["EXPIRE", "b", "100"]), Arc::clone(&server)).await;
+        run(
+            command(&["SET", "c", "1", "PX", "100000"]),
+            Arc::clone(&server),
+        )
+        .await;
+        assert_eq!(volatile(&server).await, ["a", "b", "c"]);
+        run(command(&["PERSIST", "a"]), Arc::clone(&server)).await;
+        run(command(&["DEL", "b"]), Arc::clone(&server)).await;
+        assert_eq!(volatile(&server).await, ["c"]);
+        run(command(&["SET", "c", "2", "KEEPTTL"]), Arc::clone(&server)).await;
+        assert_eq!(volatile(&server).await, ["c"]);
+        run(command(&["SET", "c", "3"]), Arc::clone(&server)).await;
+        assert!(volatile(&server).await.is_empty());
+        run(
+            command(&["SET", "d", "1", "EX", "100"]),
+            Arc::clone(&server),
+        )
+        .await;
+        run(command(&["FLUSHDB"]), Arc::clone(&server)).await;
+        assert!(volatile(&server).await.is_empty());
+        assert!(server.dbs[0].read().await.volatile_pos.is_empty());
+    }
+    #[tokio::test]
+    async fn test_pipelined_replies_are_batched() {
+        let server = Arc::new(Server::new(Config::default()));
+        let data = b"*1\r\n$4\r\nPING\r\n*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\n";
+        assert_eq!(
+            handle_buffer(&mut data.to_vec(), &server, &mut Client::default()).await,
+            (b"+PONG\r\n+OK\r\n$1\r\nv\r\n".to_vec(), true)
+        );
+    }
+    #[tokio::test]
+    async fn test_batch_stops_at_protocol_error() {
+        let server = Arc::new(Server::new(Config::default()));
+        let data = b"*1\r\n$4\r\nPING\r\n*abc\r\n*1\r\n$4\r\nPING\r\n";
+        assert_eq!(
+            handle_buffer(&mut data.to_vec(), &server, &mut Client::default()).await,
+            (
+                b"+PONG\r\n-ERR Protocol error: invalid multibulk length\r\n".to_vec(),
+                false
+            )
+        );
+    }
+    #[tokio::test]
+    async fn test_unterminated_line_is_capped() {
+        let server = Arc::new(Server::new(Config::default()));
+        let mut buffer = [b"*".to_vec(), b"1".repeat(MAX_INLINE_LEN)].concat();
+        assert_eq!(
+            handle_buffer(&mut buffer, &server, &mut Client::default()).await,
+            (Vec::new(), true)
+        );
+        buffer.push(b'1');
+        assert_eq!(
+            handle_buffer(&mut buffer, &server, &mut Client::default()).await,
+            (
+                b"-ERR Protocol error: too big inline request\r\n".to_vec(),
+                false
+            )
+        );
+    }
+    #[tokio::test]
+    async fn test_command_docs_for_single_command() {
+        let server = Arc::new(Server::new(Config::default()));
+        let reply = text(run(command(&["COMMAND", "DOCS", "get"]), server).await);
+        assert!(reply.starts_with("*2\r\n$3\r\nget\r\n*"));
+        assert!(reply.contains("$7\r\nsummary\r\n$34\r\nReturns the string value of a key.\r\n"));
+        assert!(reply.contains("$5\r\ngroup\r\n$6\r\nstring\r\n"));
+    }
+    #[tokio::test]
+    async fn test_command_docs_for_all_commands() {
+        let server = Arc::new(Server::new(Config::default()));
+        let reply = text(run(command(&["COMMAND", "DOCS"]), server).await);
+        assert!(reply.starts_with(&format!("*{}\r\n", COMMANDS.len() * 2)));
+        for spec in COMMANDS {
+            let name = spec.name.to_ascii_lowercase();
+            assert!(
+                reply.contains(&format!("\r\n${}\r\n{}\r\n*", name.len(), name)),
+                "{}",
+                name
+            );
+        }
+    }
+    #[tokio::test]
+    async fn test_select_single_database() {
+        let config = Config::from_args(args(&["--databases", "1"])).expect("config");
+        let server = Arc::new(Server::new(config));
+        assert_eq!(
+            run(command(&["SELECT", "0"]), Arc::clone(&server)).await,
+            "+OK\r\n"
+        );
+        assert_eq!(
+            run(command(&["SELECT", "1"]), Arc::clone(&server)).await,
+            "-ERR DB index is out of range\r\n"
+        );
+        assert_eq!(
+            run(command(&["SELECT", "-1"]), Arc::clone(&server)).await,
+            "-ERR DB index is out of range\r\n"
+        );
+        assert_eq!(
+            run(command(&["SELECT", "one"]), server).await,
+            "-ERR value is not an integer or out of range\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_client_task_exits_when_connection_closes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let client = TcpStream::connect(addr).await.expect("connect");
+        let (stream, _) = listener.accept().await.expect("accept");
+        let server = Arc::new(Server::new(Config::default()));
+        let task = tokio::spawn(handle_client(server, stream));
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .expect("client task should exit")
+            .expect("client task should not panic");
+    }
+    async fn spawn_server() -> std::net::SocketAddr {
+        spawn_server_with(Arc::new(Server::new(Config::default()))).await
+    }
+    async fn spawn_server_with(server: Arc<Server>) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        tokio::spawn(run_server(listener, server));
+        addr
+    }
+    #[tokio::test]
+    async fn test_server_replies_to_ping() {
+        let mut client = TcpStream::connect(spawn_server().await)
+            .await
+            .expect("connect");
+        client
+            .write_all(b"*1\r\n$4\r\nPING\r\n")
+            .await
+            .expect("write");
+        let mut reply = [0; 7];
+        client.read_exact(&mut reply).await.expect("read");
+        assert_eq!(&reply, b"+PONG\r\n");
+    }
+    async fn send(stream: &mut TcpStream, args: &[&str]) -> String {
+        let request = Value::Array(
+            args.iter()
+                .map(|x| Value::BulkString(Bytes::copy_from_slice(x.as_bytes())))
+                .collect(),
+        );
+        stream.write_all(&serialize(&request)).await.expect("write");
+        let mut reply = vec![0; 64];
+        let n = stream.read(&mut reply).await.expect("read");
+        String::from_utf8_lossy(&reply[..n]).into_owned()
+    }
+    #[tokio::test]
+    async fn test_client_kill_closes_the_other_connection() {
+        let addr = spawn_server().await;
+        let mut victim = TcpStream::connect(addr).await.expect("connect");
+        let mut killer = TcpStream::connect(addr).await.expect("connect");
+        let victim_id = send(&mut victim, &["CLIENT", "ID"]).await;
+        let killer_id = send(&mut killer, &["CLIENT", "ID"]).await;
+        assert_ne!(victim_id, killer_id);
+        let victim_id = victim_id.trim_start_matches(':').trim_end();
+        assert_eq!(
+            send(&mut killer, &["CLIENT", "KILL", "ID", victim_id]).await,
+            ":1\r\n"
+        );
+        let mut rest = Vec::new();
+        let n = tokio::time::timeout(Duration::from_secs(5), victim.read_to_end(&mut rest))
+            .await
+            .expect("victim was not closed")
+            .unwrap_or(0);
+        assert_eq!(n, 0);
+        assert_eq!(
+            send(&mut killer, &["CLIENT", "KILL", "ID", victim_id]).await,
+            ":0\r\n"
+        );
+        assert_eq!(send(&mut killer, &["PING"]).await, "+PONG\r\n");
+    }
+    #[tokio::test]
+    async fn test_client_kill_by_addr() {
+        let addr = spawn_server().await;
+        let mut victim = TcpStream::connect(addr).await.expect("connect");
+        let mut killer = TcpStream::connect(addr).await.expect("connect");
+        assert_eq!(send(&mut victim, &["PING"]).await, "+PONG\r\n");
+        let victim_addr = victim.local_addr().expect("addr").to_string();
+        assert_eq!(
+            send(&mut killer, &["CLIENT", "KILL", &victim_addr]).await,
+            "+OK\r\n"
+        );
+        let mut rest = Vec::new();
+        let n = tokio::time::timeout(Duration::from_secs(5), victim.read_to_end(&mut rest))
+            .await
+            .expect("victim was not closed")
+            .unwrap_or(0);
+        assert_eq!(n, 0);
+        assert_eq!(
+            send(&mut killer, &["CLIENT", "KILL", &victim_addr]).await,
+            "-ERR No such client\r\n"
+        );
+        let killer_addr = killer.local_addr().expect("addr").to_string();
+        assert_eq!(
+            send(&mut killer, &["CLIENT", "KILL", "ADDR", &killer_addr]).await,
+            ":0\r\n"
+        );
+        for args in [
+            &["CLIENT", "KILL"][..],
+            &["CLIENT", "KILL", "ID", "1", "ADDR"],
+            &["CLIENT", "KILL", "FOO", "bar"],
+        ] {
+            assert_eq!(send(&mut killer, args).await, "-ERR syntax error\r\n");
+        }
+        assert_eq!(
+            send(&mut killer, &["CLIENT", "KILL", "ID", "0"]).await,
+            "-ERR client-id should be greater than 0\r\n"
+        );
+    }
+    #[test]
+    fn test_parse_bulk_string_with_crlf_in_payload() {
+        let data = b"$8\r\nab\r\ncd\r\n\r\n";
+        let mut p = Parser::new(data);
+        assert_eq!(
+            p.parse_value(),
+            Ok(Value::BulkString(Bytes::from("ab\r\ncd\r\n")))
+        );
+        assert_eq!(p.pos, data.len());
+    }
+    #[test]
+    fn test_parse_bulk_string_longer_than_declared() {
+        let data = b"$5\r\nabcdef\r\n";
+        let mut p = Parser::new(data);
+        assert_eq!(p.parse_value(), Err(ParseError::MissingBulkTerminator));
+    }
+    #[test]
+    fn test_parse_bulk_string_invalid_length() {
+        let data = b"$x\r\nabc\r\n";
+        let mut p = Parser::new(data);
+        assert_eq!(p.parse_value(), Err(ParseError::InvalidBulkLength));
+    }
+    #[tokio::test]
+    async fn test_client_over_output_buffer_limit_is_disconnected() {
+        let config = Config {
+            maxmemory_clients: 64 * 1024,
+            ..Config::default()
+        };
+        let server = Arc::new(Server::new(config));
+        server.dbs[0].write().await.insert(
+            Bytes::from("big"),
+            StoredValue::new("x".repeat(1024 * 1024).into(), None),
+        );
+        let mut client = TcpStream::connect(spawn_server_with(server).await)
+            .await
+            .expect("connect");
+        client
+            .write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nbig\r\nPING\r\n")
+            .await
+            .expect("write");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut reply = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut reply))
+            .await
+            .expect("connection should be closed")
+            .expect("read");
+        assert_eq!(reply.len(), 1024 * 1024 + 12);
+        assert!(reply.starts_with(b"$1048576\r\nxxx"));
+        assert!(reply.ends_with(b"xxx\r\n"));
+    }
+    #[tokio::test]
+    async fn test_output_limit_counts_unsent_replies() {
+        let config = Config {
+            maxmemory_clients: 16,
+            ..Config::default()
+        };
+        let server = Arc::new(Server::new(config));
+        let mut client = Client {
+            pending_output: 8,
+            ..Client::default()
+        };
+        assert_eq!(
+            handle_buffer(
+                &mut b"SET k v\r\nPING\r\nSET k w\r\n".to_vec(),
+                &server,
+                &mut client
+            )
+            .await,
+            (b"+OK\r\n+PONG\r\n".to_vec(), false)
+        );
+        assert_eq!(
+            server.dbs[0].read().await[&b"k"[..]].value,
+            StoredData::String(Bytes::from("v"))
+        );
+    }
+    #[test]
+    fn test_sha1_hex() {
+        assert_eq!(sha1_hex(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(sha1_hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(
+            sha1_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+        assert_eq!(
+            sha1_hex(&[b'a'; 1000]),
+            "291e9a6c66994949b57ba5e650361e98fc36b1ba"
+        );
+    }
+    #[tokio::test]
+    async fn test_script_load_returns_sha() {
+        let server = Arc::new(Server::new(Config::default()));
+        assert_eq!(
+            run(
+                command(&["SCRIPT", "LOAD", "return 1"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "$40\r\ne0e1f9fabfc9d4800c877a703b823ac0578ff8db\r\n"
+        );
+        assert_eq!(
+            run(
+                command(&[
+                    "SCRIPT",
+                    "EXISTS",
+                    "E0E1F9FABFC9D4800C877A703B823AC0578FF8DB",
+                    "da39a3ee5e6b4b0d3255bfef95601890afd80709"
+                ]),
+                Arc::clone(&server)
+            )
+            .await,
+            "*2\r\n:1\r\n:0\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_eval_is_not_supported() {
+        let server = Arc::new(Server::new(Config::default()));
+        assert_eq!(
+            run(command(&["EVAL", "return 1", "0"]), Arc::clone(&server)).await,
+            "-ERR This Redis build does not support scripting\r\n"
+        );
+        let sha = "e0e1f9fabfc9d4800c877a703b823ac0578ff8db";
+        assert_eq!(
+            run(command(&["EVALSHA", sha, "0"]), Arc::clone(&server)).await,
+            "-NOSCRIPT No matching script. Please use EVAL.\r\n"
+        );
+        run(
+            command(&["SCRIPT", "LOAD", "return 1"]),
+            Arc::clone(&server),
+        )
+        .await;
+        assert_eq!(
+            run(command(&["EVALSHA", sha, "0"]), Arc::clone(&server)).await,
+            "-ERR This Redis build does not support scripting\r\n"
+        );
+        run(command(&["SCRIPT", "FLUSH"]), Arc::clone(&server)).await;
+        assert_eq!(
+            run(command(&["EVALSHA", sha, "0"]), server).await,
+            "-NOSCRIPT No matching script. Please use EVAL.\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_every_unknown_subcommand_hint_has_help() {
+        let server = Arc::new(Server::new(Config::default()));
+        for name in ["OBJECT", "SCRIPT", "DEBUG", "CLIENT", "COMMAND"] {
+            let error = text(run(command(&[name, "nope"]), Arc::clone(&server)).await);
+            assert!(
+                error.ends_with(&format!("Try {} HELP.\r\n", name)),
+                "{}",
+                error
+            );
+            let help = text(run(command(&[name, "HELP"]), Arc::clone(&server)).await);
+            assert!(
+                help.contains(&format!("\r\n+{} <subcommand>", name)),
+                "{}",
+                help
+            );
+            assert!(
+                help.ends_with("+HELP\r\n+    Print this help.\r\n"),
+                "{}",
+                help
+            );
+        }
+        assert_eq!(
+            run(command(&["SCRIPT", "EXISTS"]), server).await,
+            "-ERR wrong number of arguments for 'script|exists' command\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_debug_subcommands() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(command(&["SET", "key", "hello"]), Arc::clone(&server)).await;
+        let help = text(run(command(&["DEBUG", "help"]), Arc::clone(&server)).await);
+        assert!(help.starts_with("*11\r\n+DEBUG <subcommand>"));
+        assert_eq!(
+            run(
+                command(&["DEBUG", "SET-ACTIVE-EXPIRE", "0"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "+OK\r\n"
+        );
+        assert!(!server.active_expire.load(Ordering::Relaxed));
+        assert_eq!(
+            run(command(&["DEBUG", "SLEEP", "0.01"]), Arc::clone(&server)).await,
+            "+OK\r\n"
+        );
+        let object = text(run(command(&["DEBUG", "OBJECT", "key"]), Arc::clone(&server)).await);
+        assert!(
+            object.starts_with("+Value at:0x0 refcount:1 encoding:embstr serializedlength:5 lru:")
+        );
+        assert!(object.ends_with(" lru_seconds_idle:0 age:0\r\n"));
+        assert_eq!(
+            run(
+                command(&["DEBUG", "OBJECT", "missing"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "-ERR no such key\r\n"
+        );
+        assert_eq!(
+            run(command(&["DEBUG", "RELOAD"]), Arc::clone(&server)).await,
+            "-ERR unknown subcommand 'RELOAD'. Try DEBUG HELP.\r\n"
+        );
+        assert_eq!(
+            run(
+                command(&["DEBUG", "SET-ACTIVE-EXPIRE", "1"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "+OK\r\n"
+        );
+        assert!(server.active_expire.load(Ordering::Relaxed));
+    }
+    #[test]
+    fn test_string_encoding() {
+        assert_eq!(string_encoding(b"12345"), "int");
+        assert_eq!(string_encoding(b"hello"), "embstr");
+        assert_eq!(string_encoding("x".repeat(44).as_bytes()), "embstr");
+        assert_eq!(string_encoding("x".repeat(45).as_bytes()), "raw");
+    }
+    #[test]
+    fn test_parse_inline_command() {
+        let data = b"PING\n";
+        let mut p = Parser::new(data);
+        assert_eq!(
+            p.parse_value(),
+            Ok(Value::Array(vec![Value::BulkString(Bytes::from("PING"))]))
+        );
+        assert_eq!(p.pos, data.len());
+    }
+    #[test]
+    fn test_parse_inline_command_with_leading_spaces() {
+        let data = b"   SET  key \tvalue\r\n";
+        let mut p = Parser::new(data);
+        assert_eq!(
+            p.parse_value(),
+            Ok(Value::Array(vec![
+                Value::BulkString(Bytes::from("SET")),
+                Value::BulkString(Bytes::from("key")),
+                Value::BulkString(Bytes::from("value"))
+            ]))
+        );
+        assert_eq!(p.pos, data.len());
+    }
+    #[test]
+    fn test_parse_lf_terminated_resp_lines() {
+        let data = b"*2\n+AB\n+CD\n";
+        let mut p = Parser::new(data);
+        assert_eq!(
+            p.parse_value(),
+            Ok(Value::Array(vec![
+                Value::SimpleString(String::from("AB")),
+                Value::SimpleString(String::from("CD"))
+            ]))
+        );
+    }
+    #[tokio::test]
+    async fn test_inline_commands_are_executed() {
+        let server = Arc::new(Server::new(Config::default()));
+        assert_eq!(
+            handle_buffer(
+                &mut b"PING\n\r\n  ECHO hey\r\n".to_vec(),
+                &server,
+                &mut Client::default()
+            )
+            .await,
+            (b"+PONG\r\n$3\r\nhey\r\n".to_vec(), true)
+        );
+    }
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"*", b"", false));
+        assert!(glob_match(b"user:*", b"user:1", false));
+        assert!(!glob_match(b"user:*", b"session:1", false));
+        assert!(glob_match(b"h?llo", b"hello", false));
+        assert!(!glob_match(b"h?llo", b"hllo", false));
+        assert!(glob_match(b"h[ae]llo", b"hallo", false));
+        assert!(!glob_match(b"h[ae]llo", b"hillo", false));
+        assert!(glob_match(b"h[^e]llo", b"hallo", false));
+        assert!(!glob_match(b"h[^e]llo", b"hello", false));
+        assert!(glob_match(b"h[a-c]llo", b"hbllo", false));
+        assert!(!glob_match(b"h[a-c]llo", b"hdllo", false));
+        assert!(glob_match(b"h\\*llo", b"h*llo", false));
+        assert!(!glob_match(b"h\\*llo", b"hello", false));
+        assert!(glob_match(b"*a*b*", b"xxaxxbxx", false));
+        assert!(!glob_match(b"GET*", b"getrange", false));
+        assert!(glob_match(b"GET*", b"getrange", true));
+    }
+    #[tokio::test]
+    async fn test_command_list_filterby_pattern() {
+        let server = Arc::new(Server::new(Config::default()));
+        assert_eq!(
+            run(
+                command(&["COMMAND", "LIST", "FILTERBY", "PATTERN", "get*"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "*3\r\n$3\r\nget\r\n$6\r\ngetset\r\n$8\r\ngetrange\r\n"
+        );
+        let all = text(run(command(&["COMMAND", "LIST"]), Arc::clone(&server)).await);
+        assert!(all.starts_with(&format!("*{}\r\n", COMMANDS.len())));
+        assert_eq!(
+            run(
+                command(&["COMMAND", "LIST", "FILTERBY", "ACLCAT", "string"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "*0\r\n"
+        );
+        assert_eq!(
+            run(command(&["COMMAND", "LIST", "FILTERBY"]), server).await,
+            "-ERR syntax error\r\n"
+        );
+    }
+    #[test]
+    fn test_lfu_counter_is_logarithmic_and_decays() {
+        let value = StoredValue::new(Bytes::from("v"), None);
+        assert_eq!(value.frequency.load(Ordering::Relaxed), LFU_INIT_VAL);
+        value.touch();
+        assert_eq!(value.frequency.load(Ordering::Relaxed), LFU_INIT_VAL + 1);
+        for _ in 0..1000 {
+            value.touch();
+        }
+        let counter = value.frequency.load(Ordering::Relaxed);
+        assert!(counter > LFU_INIT_VAL + 1 && counter < 40, "{}", counter);
+        value.frequency.store(10, Ordering::Relaxed);
+        value
+            .last_access
+            .store(get_time() as u64 - 3 * LFU_DECAY_MS, Ordering::Relaxed);
+        value.touch();
+        assert!(
+            matches!(value.frequency.load(Ordering::Relaxed), 7 | 8),
+            "{}",
+            value.frequency.load(Ordering::Relaxed)
+        );
+    }
+    #[tokio::test]
+    async fn test_get_updates_access_metadata() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(command(&["SET", "key", "value"]), Arc::clone(&server)).await;
+        server.dbs[0].read().await[&b"key"[..]]
+            .last_access
+            .store(0, Ordering::Relaxed);
+        assert!(server.dbs[0].read().await[&b"key"[..]].idle_time() > 1000);
+        for _ in 0..3 {
+            run(command(&["GET", "key"]), Arc::clone(&server)).await;
+        }
+        let readable = server.dbs[0].read().await;
+        assert!(readable[&b"key"[..]].idle_time() < 1000);
+        assert_eq!(readable[&b"key"[..]].frequency.load(Ordering::Relaxed), 3);
+    }
+    #[tokio::test]
+    async fn test_reads_and_writes_update_access_metadata() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(command(&["SET", "s", "1"]), Arc::clone(&server)).await;
+        run(command(&["HSET", "h", "f", "v"]), Arc::clone(&server)).await;
+        run(command(&["RPUSH", "l", "a"]), Arc::clone(&server)).await;
+        run(command(&["PFADD", "p", "a"]), Arc::clone(&server)).await;
+        for args in [
+            &["GET", "s"][..],
+            &["MGET", "s"],
+            &["EXISTS", "s"],
+            &["STRLEN", "s"],
+            &["GETRANGE", "s", "0", "-1"],
+            &["INCR", "s"],
+            &["APPEND", "s", "0"],
+            &["SETRANGE", "s", "0", "2"],
+            &["EXPIRE", "s", "100"],
+            &["PERSIST", "s"],
+            &["HGET", "h", "f"],
+            &["HGETALL", "h"],
+            &["HSET", "h", "g", "v"],
+            &["LRANGE", "l", "0", "-1"],
+            &["LLEN", "l"],
+            &["LPUSH", "l", "b"],
+            &["RPUSH", "l", "c"],
+            &["PFADD", "p", "b"],
+            &["PFCOUNT", "p"],
+        ] {
+            let key = args[1].as_bytes();
+            let created_at = {
+                let readable = server.dbs[0].read().await;
+                readable[key].last_access.store(0, Ordering::Relaxed);
+                readable[key].frequency.store(0, Ordering::Relaxed);
+                readable[key].created_at
+            };
+            run(command(args), Arc::clone(&server)).await;
+            let readable = server.dbs[0].read().await;
+            assert!(readable[key].idle_time() < 1000, "{:?}", args);
+            assert_eq!(
+                readable[key].frequency.load(Ordering::Relaxed),
+                1,
+                "{:?}",
+                args
+            );
+            assert_eq!(readable[key].created_at, created_at, "{:?}", args);
+        }
+    }
+    #[tokio::test]
+    async fn test_object_and_ttl_do_not_update_access_metadata() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(command(&["SET", "s", "1"]), Arc::clone(&server)).await;
+        server.dbs[0].read().await[&b"s"[..]]
+            .last_access
+            .store(0, Ordering::Relaxed);
+        for args in [
+            &["OBJECT", "IDLETIME", "s"][..],
+            &["TTL", "s"],
+            &["DEBUG", "OBJECT", "s"],
+        ] {
+            run(command(args), Arc::clone(&server)).await;
+        }
+        assert!(server.dbs[0].read().await[&b"s"[..]].idle_time() > 1000);
+    }
+    #[tokio::test]
+    async fn test_pfcount_estimates_cardinality() {
+        let server = Arc::new(Server::new(Config::default()));
+        let mut elements = vec!["PFADD".to_string(), "hll".to_string()];
+        elements.extend((0..1000).map(|i| format!("element:{}", i)));
+        let elements: Vec<&str> = elements.iter().map(String::as_str).collect();
+        assert_eq!(run(command(&elements), Arc::clone(&server)).await, ":1\r\n");
+        assert_eq!(
+            run(command(&elements[..3]), Arc::clone(&server)).await,
+            ":0\r\n"
+        );
+        let reply = text(run(command(&["PFCOUNT", "hll"]), Arc::clone(&server)).await);
+        let count: i64 = reply[1..reply.len() - 2].parse().expect("integer");
+        assert!((990..=1010).contains(&count), "{}", count);
+        assert_eq!(
+            run(command(&["PFCOUNT", "missing"]), server).await,
+            ":0\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_pfadd_hashes_raw_bytes() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(
+            binary_command("PFADD", &[b"p", b"\xff", b"\xfe", b"\xfd"]),
+            Arc::clone(&server),
+        )
+        .await;
+        assert_eq!(
+            run(command(&["PFCOUNT", "p"]), Arc::clone(&server)).await,
+            ":3\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_script_load_hashes_raw_bytes() {
+        let server = Arc::new(Server::new(Config::default()));
+        assert_eq!(
+            run(
+                binary_command("SCRIPT", &[b"LOAD", b"return \xff"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "$40\r\n91c67033cd3a9baa4875d0e3c7d4538f7effc50b\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_pfmerge_unions_registers() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(command(&["PFADD", "a", "1", "2", "3"]), Arc::clone(&server)).await;
+        run(command(&["PFADD", "b", "3", "4", "5"]), Arc::clone(&server)).await;
+        assert_eq!(
+            run(command(&["PFCOUNT", "a", "b"]), Arc::clone(&server)).await,
+            ":5\r\n"
+        );
+        assert_eq!(
+            run(command(&["PFMERGE", "c", "a", "b"]), Arc::clone(&server)).await,
+            "+OK\r\n"
+        );
+        assert_eq!(
+            run(command(&["PFCOUNT", "c"]), Arc::clone(&server)).await,
+            ":5\r\n"
+        );
+        run(command(&["PFMERGE", "a", "b"]), Arc::clone(&server)).await;
+        for key in ["a", "b", "c"] {
+            assert_eq!(
+                run(command(&["OBJECT", "ENCODING", key]), Arc::clone(&server)).await,
+                "$3\r\nraw\r\n"
+            );
+        }
+        let store = server.dbs[0].read().await;
+        assert!(store[&b"c"[..]].raw);
+        assert!(store[&b"c"[..]]
+            .string()
+            .expect("string")
+            .starts_with(b"HYLL"));
+    }
+    #[tokio::test]
+    async fn test_pf_commands_reject_plain_strings() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(command(&["SET", "key", "value"]), Arc::clone(&server)).await;
+        for args in [
+            &["PFADD", "key", "a"][..],
+            &["PFCOUNT", "key"],
+            &["PFMERGE", "key"],
+        ] {
+            assert_eq!(run(command(args), Arc::clone(&server)).await, HLL_WRONGTYPE);
+        }
+        assert_eq!(
+            server.dbs[0].read().await[&b"key"[..]].value,
+            StoredData::String(Bytes::from("value"))
+        );
+    }
+    #[tokio::test]
+    async fn test_renamed_commands() {
+        let config = Config::from_args(args(&[
+            "--rename-command",
+            "ECHO",
+            "",
+            "--rename-command",
+            "get",
+            "fetch",
+            "--rename-command",
+            "debug",
+            "secret-debug",
+            "--rename-command",
+            "mset",
+            "put-many",
+            "--rename-command",
+            "hset",
+            "put-field",
+        ]))
+        .expect("config");
+        let server = Arc::new(Server::new(config));
+        assert_eq!(
+            run(command(&["ECHO", "hi"]), Arc::clone(&server)).await,
+            "-ERR unknown command 'ECHO'\r\n"
+        );
+        run(command(&["SET", "k", "v"]), Arc::clone(&server)).await;
+        assert_eq!(
+            run(command(&["GET", "k"]), Arc::clone(&server)).await,
+            "-ERR unknown command 'GET'\r\n"
+        );
+        assert_eq!(
+            run(command(&["fetch", "k"]), Arc::clone(&server)).await,
+            "$1\r\nv\r\n"
+        );
+        assert_eq!(
+            run(command(&["FETCH"]), Arc::clone(&server)).await,
+            "-ERR wrong number of arguments for 'fetch' command\r\n"
+        );
+        assert_eq!(
+            run(command(&["PUT-MANY", "a", "1", "b"]), Arc::clone(&server)).await,
+            "-ERR wrong number of arguments for 'put-many' command\r\n"
+        );
+        assert_eq!(
+            run(
+                command(&["put-field", "h", "a", "1", "b"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "-ERR wrong number of arguments for 'put-field' command\r\n"
+        );
+        assert_eq!(
+            run(command(&["DEBUG", "SLEEP", "0"]), Arc::clone(&server)).await,
+            "-ERR unknown command 'DEBUG'\r\n"
+        );
+        assert_eq!(
+            run(
+                command(&["secret-debug", "SLEEP", "0"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "+OK\r\n"
+        );
+        assert_eq!(run(command(&["PING"]), server).await, "+PONG\r\n");
+    }
+    #[tokio::test]
+    async fn test_command_introspection_follows_renames() {
+        let config = Config::from_args(args(&[
+            "--rename-command",
+            "ECHO",
+            "",
+            "--rename-command",
+            "GET",
+            "FETCH",
+        ]))
+        .expect("config");
+        let server = Arc::new(Server::new(config));
+        assert_eq!(
+            run(command(&["COMMAND", "COUNT"]), Arc::clone(&server)).await,
+            format!(":{}\r\n", COMMANDS.len() - 1)
+        );
+        let list = text(run(command(&["COMMAND", "LIST"]), Arc::clone(&server)).await);
+        assert!(list.starts_with(&format!("*{}\r\n", COMMANDS.len() - 1)));
+        assert!(list.contains("\r\nfetch\r\n"));
+        assert!(!list.contains("\r\nget\r\n"));
+        assert!(!list.contains("\r\necho\r\n"));
+        let docs = text(run(command(&["COMMAND", "DOCS", "fetch"]), Arc::clone(&server)).await);
+        assert!(docs.starts_with("*2\r\n$5\r\nfetch\r\n"));
+        assert!(docs.contains("Returns the string value of a key."));
+        for name in ["get", "echo"] {
+            assert_eq!(
+                run(command(&["COMMAND", "DOCS", name]), Arc::clone(&server)).await,
+                "*0\r\n"
+            );
+        }
+    }
+    #[tokio::test]
+    async fn test_set_exat_and_pxat() {
+        let server = Arc::new(Server::new(Config::default()));
+        let at = get_time() / 1000 + 100;
+        assert_eq!(
+            run(
+                command(&["SET", "key", "value", "EXAT", &at.to_string()]),
+                Arc::clone(&server)
+            )
+            .await,
+            "+OK\r\n"
+        );
+        assert_eq!(
+            server.dbs[0].read().await[&b"key"[..]].expiry,
+            Some(at * 1000)
+        );
+        let at = get_time() + 100_000;
+        run(
+            command(&["SET", "key", "value", "pxat", &at.to_string()]),
+            Arc::clone(&server),
+        )
+        .await;
+        assert_eq!(server.dbs[0].read().await[&b"key"[..]].expiry, Some(at));
+    }
+    #[tokio::test]
+    async fn test_set_rejects_multiple_expiry_options() {
+        let server = server_with_ttl_key().await;
+        for args in [
+            &["SET", "key", "new", "EX", "10", "PXAT", "99999999999999"][..],
+            &["SET", "key", "new", "PXAT", "99999999999999", "EX", "10"],
+            &["SET", "key", "new", "EXAT", "9999999999", "KEEPTTL"],
+            &["SET", "key", "new", "EXAT"],
+        ] {
+            assert_eq!(
+                run(command(args), Arc::clone(&server)).await,
+                "-ERR syntax error\r\n"
+            );
+        }
+        assert_eq!(
+            run(
+                command(&["SET", "key", "new", "EXAT", "0"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "-ERR invalid expire time in 'set' command\r\n"
+        );
+        for option in ["EX", "PX", "EXAT"] {
+            assert_eq!(
+                run(
+                    command(&["SET", "key", "new", option, "9223372036854775807"]),
+                    Arc::clone(&server)
+                )
+                .await,
+                "-ERR invalid expire time in 'set' command\r\n"
+            );
+        }
+        assert_eq!(
+            server.dbs[0].read().await[&b"key"[..]].value,
+            StoredData::String(Bytes::from("old"))
+        );
+    }
+    #[test]
+    fn test_token_bucket_refills_over_time() {
+        let mut bucket = TokenBucket::new(10.0);
+        bucket.tokens = 0.0;
+        bucket.last -= Duration::from_millis(500);
+        bucket.refill();
+        assert!((4.9..=5.5).contains(&bucket.tokens), "{}", bucket.tokens);
+        bucket.last -= Duration::from_secs(10);
+        bucket.refill();
+        assert_eq!(bucket.tokens, 10.0);
+    }
+    #[tokio::test]
+    async fn test_accepts_are_rate_limited() {
+        let config = Config::from_args(args(&["--max-accepts-per-sec", "20"])).expect("config");
+        let addr = spawn_server_with(Arc::new(Server::new(config))).await;
+        let start = std::time::Instant::now();
+        for _ in 0..30 {
+            let mut client = TcpStream::connect(addr).await.expect("connect");
+            client.write_all(b"PING\r\n").await.expect("write");
+            let mut reply = [0; 7];
+            client.read_exact(&mut reply).await.expect("read");
+            assert_eq!(&reply, b"+PONG\r\n");
+        }
+        // The first 20 connections use up the burst, the other 10 wait for refills.
+        assert!(start.elapsed() >= Duration::from_millis(450));
+    }
+    #[test]
+    fn test_parse_incomplete_frames() {
+        let frame = b"*2\r\n$4\r\nECHO\r\n$5\r\nhello\r\n";
+        for end in 0..frame.len() {
+            let mut p = Parser::new(&frame[..end]);
+            assert_eq!(p.parse_value(), Err(ParseError::Incomplete), "{}", end);
+        }
+        assert_eq!(
+            Parser::new(b"PING").parse_value(),
+            Err(ParseError::Incomplete)
+        );
+    }
+    #[tokio::test]
+    async fn test_partial_frame_is_kept_for_the_next_read() {
+        let server = Arc::new(Server::new(Config::default()));
+        let mut buffer = b"*1\r\n$4\r\nPING\r\n*2\r\n$4\r\nECHO\r\n$2\r\nh".to_vec();
+        assert_eq!(
+            handle_buffer(&mut buffer, &server, &mut Client::default()).await,
+            (b"+PONG\r\n".to_vec(), true)
+        );
+        assert_eq!(buffer, b"*2\r\n$4\r\nECHO\r\n$2\r\nh");
+        buffer.extend_from_slice(b"i\r\n");
+        assert_eq!(
+            handle_buffer(&mut buffer, &server, &mut Client::default()).await,
+            (b"$2\r\nhi\r\n".to_vec(), true)
+        );
+        assert!(buffer.is_empty());
+    }
+    #[tokio::test]
+    async fn test_partial_bulk_is_not_parsed_until_complete() {
+        let server = Arc::new(Server::new(Config::default()));
+        let mut client = Client::default();
+        let mut buffer = b"*1\r\n$4\r\nPING\r\n*2\r\n$4\r\nECHO\r\n$10\r\nab".to_vec();
+        assert_eq!(
+            handle_buffer(&mut buffer, &server, &mut client).await,
+            (b"+PONG\r\n".to_vec(), true)
+        );
+        assert_eq!(client.needed, buffer.len() + 8);
+        for chunk in [&b"cdef"[..], b"ghij", b"\r"] {
+            buffer.extend_from_slice(chunk);
+            assert_eq!(
+                handle_buffer(&mut buffer, &server, &mut client).await,
+                (Vec::new(), true)
+            );
+        }
+        buffer.extend_from_slice(b"\n");
+        assert_eq!(
+            handle_buffer(&mut buffer, &server, &mut client).await,
+            (b"$10\r\nabcdefghij\r\n".to_vec(), true)
+        );
+        assert!(buffer.is_empty());
+        assert_eq!(client.needed, 0);
+    }
+    #[tokio::test]
+    async fn test_set_and_get_large_value() {
+        let addr = spawn_server().await;
+        let mut client = TcpStream::connect(addr).await.expect("connect");
+        let value = "x".repeat(200 * 1024);
+        let set = format!(
+            "*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n${}\r\n{}\r\n",
+            value.len(),
+            value
+        );
+        client.write_all(set.as_bytes()).await.expect("write");
+        let mut reply = [0; 5];
+        client.read_exact(&mut reply).await.expect("read");
+        assert_eq!(&reply, b"+OK\r\n");
+        client
+            .write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n")
+            .await
+            .expect("write");
+        let expected = format!("${}\r\n{}\r\n", value.len(), value);
+        let mut reply = vec![0; expected.len()];
+        client.read_exact(&mut reply).await.expect("read");
+        assert_eq!(reply, expected.as_bytes());
+    }
+    #[tokio::test]
+    async fn test_unknown_command_keeps_connection_open() {
+        let addr = spawn_server().await;
+        let mut client = TcpStream::connect(addr).await.expect("connect");
+        client
+            .write_all(b"*2\r\n$6\r\nFOOBAR\r\n$1\r\nx\r\n")
+            .await
+            .expect("write");
+        let expected = b"-ERR unknown command 'FOOBAR'\r\n";
+        let mut reply = [0; 31];
+        client.read_exact(&mut reply).await.expect("read");
+        assert_eq!(&reply, expected);
+        client.write_all(b"PING\r\n").await.expect("write");
+        let mut reply = [0; 7];
+        client.read_exact(&mut reply).await.expect("read");
+        assert_eq!(&reply, b"+PONG\r\n");
+    }
+    #[tokio::test]
+    async fn test_unknown_names_are_sanitized_in_errors() {
+        let server = Arc::new(Server::new(Config::default()));
+        assert_eq!(
+            run(command(&["FOO\r\n+OK"]), Arc::clone(&server)).await,
+            "-ERR unknown command 'FOO  +OK'\r\n"
+        );
+        assert_eq!(
+            run(command(&["DEBUG", "x\r\n:1"]), Arc::clone(&server)).await,
+            "-ERR unknown subcommand 'x  :1'. Try DEBUG HELP.\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_non_string_command_is_a_protocol_error() {
+        let server = Arc::new(Server::new(Config::default()));
+        assert_eq!(
+            handle_buffer(
+                &mut b"*2\r\n$4\r\nECHO\r\n:1\r\n".to_vec(),
+                &server,
+                &mut Client::default()
+            )
+            .await,
             (
-                "ECHO".to_string(),
-                vec![Value::BulkString(String::from("hey"))]
+                b"-ERR Protocol error: expected an array of bulk strings\r\n".to_vec(),
+                false
+            )
+        );
+    }
+    #[test]
+    fn test_parse_binary_bulk_string() {
+        let mut p = Parser::new(b"$6\r\na\r\n\0\xffb\r\n");
+        assert_eq!(
+            p.parse_value(),
+            Ok(Value::BulkString(Bytes::from_static(b"a\r\n\0\xffb")))
+        );
+    }
+    #[tokio::test]
+    async fn test_echo_replies_with_a_bulk_string() {
+        let server = Arc::new(Server::new(Config::default()));
+        assert_eq!(
+            run(binary_command("ECHO", &[b"a\r\n:123"]), Arc::clone(&server)).await,
+            "$7\r\na\r\n:123\r\n"
+        );
+        assert_eq!(
+            run(binary_command("ECHO", &[b"\xff\x00"]), server).await,
+            &b"$2\r\n\xff\x00\r\n"[..]
+        );
+    }
+    #[tokio::test]
+    async fn test_set_and_get_binary_value() {
+        let addr = spawn_server().await;
+        let mut client = TcpStream::connect(addr).await.expect("connect");
+        client
+            .write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nbin\r\n$7\r\n\r\n\0x\xff\r\n\r\n")
+            .await
+            .expect("write");
+        let mut reply = [0; 5];
+        client.read_exact(&mut reply).await.expect("read");
+        assert_eq!(&reply, b"+OK\r\n");
+        client
+            .write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nbin\r\n")
+            .await
+            .expect("write");
+        let mut reply = [0; 13];
+        client.read_exact(&mut reply).await.expect("read");
+        assert_eq!(&reply, b"$7\r\n\r\n\0x\xff\r\n\r\n");
+    }
+    #[test]
+    fn test_parse_ten_byte_bulk_string() {
+        let mut p = Parser::new(b"$10\r\n0123456789\r\n");
+        assert_eq!(
+            p.parse_value(),
+            Ok(Value::BulkString(Bytes::from("0123456789")))
+        );
+        assert_eq!(p.pos, 17);
+    }
+    #[test]
+    fn test_parse_multi_digit_bulk_length() {
+        let value = "a\rb".repeat(411);
+        let data = format!("${}\r\n{}\r\n", value.len(), value);
+        let mut p = Parser::new(data.as_bytes());
+        assert_eq!(p.parse_value(), Ok(Value::BulkString(Bytes::from(value))));
+        assert_eq!(p.pos, data.len());
+    }
+    #[tokio::test]
+    async fn test_del_counts_removed_keys() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(command(&["SET", "a", "1"]), Arc::clone(&server)).await;
+        run(command(&["SET", "b", "2"]), Arc::clone(&server)).await;
+        assert_eq!(
+            run(command(&["DEL", "a", "b", "missing"]), Arc::clone(&server)).await,
+            ":2\r\n"
+        );
+        assert_eq!(
+            run(command(&["GET", "a"]), Arc::clone(&server)).await,
+            "$-1\r\n"
+        );
+        assert_eq!(
+            run(command(&["GET", "b"]), Arc::clone(&server)).await,
+            "$-1\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_del_ignores_expired_keys() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(
+            command(&["SET", "a", "1", "PXAT", "1"]),
+            Arc::clone(&server),
+        )
+        .await;
+        assert_eq!(
+            run(command(&["DEL", "a"]), Arc::clone(&server)).await,
+            ":0\r\n"
+        );
+        assert!(server.dbs[0].read().await.entries.is_empty());
+    }
+    #[tokio::test]
+    async fn test_exists_counts_live_keys() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(command(&["SET", "a", "1"]), Arc::clone(&server)).await;
+        run(
+            command(&["SET", "gone", "1", "PXAT", "1"]),
+            Arc::clone(&server),
+        )
+        .await;
+        assert_eq!(
+            run(
+                command(&["EXISTS", "a", "a", "missing"]),
+                Arc::clone(&server)
+            )
+            .await,
+            ":2\r\n"
+        );
+        assert_eq!(
+            run(command(&["EXISTS", "gone"]), Arc::clone(&server)).await,
+            ":0\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_incr_creates_and_increments_counter() {
+        let server = Arc::new(Server::new(Config::default()));
+        assert_eq!(
+            run(command(&["INCR", "counter"]), Arc::clone(&server)).await,
+            ":1\r\n"
+        );
+        run(
+            command(&["SET", "n", "41", "EX", "100"]),
+            Arc::clone(&server),
+        )
+        .await;
+        assert_eq!(
+            run(command(&["INCR", "n"]), Arc::clone(&server)).await,
+            ":42\r\n"
+        );
+        assert!(server.dbs[0].read().await[&b"n"[..]].expiry.is_some());
+        assert_eq!(
+            run(command(&["DECR", "fresh"]), Arc::clone(&server)).await,
+            ":-1\r\n"
+        );
+        assert_eq!(
+            run(command(&["GET", "n"]), Arc::clone(&server)).await,
+            "$2\r\n42\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_incr_rejects_non_integer_values() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(command(&["SET", "s", "abc"]), Arc::clone(&server)).await;
+        assert_eq!(
+            run(command(&["INCR", "s"]), Arc::clone(&server)).await,
+            "-ERR value is not an integer or out of range\r\n"
+        );
+        run(
+            command(&["SET", "max", &i64::MAX.to_string()]),
+            Arc::clone(&server),
+        )
+        .await;
+        assert_eq!(
+            run(command(&["INCR", "max"]), Arc::clone(&server)).await,
+            "-ERR increment or decrement would overflow\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_client_pause_write_blocks_only_writes() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(command(&["SET", "k", "v"]), Arc::clone(&server)).await;
+        assert_eq!(
+            run(
+                command(&["CLIENT", "PAUSE", "300", "WRITE"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "+OK\r\n"
+        );
+        let start = std::time::Instant::now();
+        let set = tokio::spawn(run(command(&["SET", "k", "w"]), Arc::clone(&server)));
+        assert_eq!(
+            run(command(&["GET", "k"]), Arc::clone(&server)).await,
+            "$1\r\nv\r\n"
+        );
+        assert!(start.elapsed() < Duration::from_millis(200));
+        assert_eq!(set.await.expect("set"), "+OK\r\n");
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        assert_eq!(
+            run(command(&["GET", "k"]), Arc::clone(&server)).await,
+            "$1\r\nw\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_client_unpause_resumes_early() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(command(&["CLIENT", "PAUSE", "10000"]), Arc::clone(&server)).await;
+        let start = std::time::Instant::now();
+        let ping = tokio::spawn(run(command(&["PING"]), Arc::clone(&server)));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!ping.is_finished());
+        run(command(&["CLIENT", "UNPAUSE"]), Arc::clone(&server)).await;
+        assert_eq!(ping.await.expect("ping"), "+PONG\r\n");
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(
+            run(command(&["CLIENT", "PAUSE", "-1"]), Arc::clone(&server)).await,
+            "-ERR timeout is not an integer or out of range\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_ttl_and_pttl() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(command(&["SET", "forever", "v"]), Arc::clone(&server)).await;
+        run(
+            command(&["SET", "soon", "v", "EX", "100"]),
+            Arc::clone(&server),
+        )
+        .await;
+        assert_eq!(
+            run(command(&["TTL", "forever"]), Arc::clone(&server)).await,
+            ":-1\r\n"
+        );
+        assert_eq!(
+            run(command(&["PTTL", "missing"]), Arc::clone(&server)).await,
+            ":-2\r\n"
+        );
+        assert_eq!(
+            run(command(&["TTL", "soon"]), Arc::clone(&server)).await,
+            ":100\r\n"
+        );
+        let pttl = text(run(command(&["PTTL", "soon"]), Arc::clone(&server)).await);
+        let pttl: i64 = pttl[1..pttl.len() - 2].parse().expect("integer");
+        assert!((99_000..=100_000).contains(&pttl), "{}", pttl);
+    }
+    #[tokio::test]
+    async fn test_set_without_expiry_is_persistent() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(command(&["SET", "k", "v"]), Arc::clone(&server)).await;
+        assert_eq!(server.dbs[0].read().await[&b"k"[..]].expiry, None);
+        assert_eq!(
+            run(command(&["GET", "k"]), Arc::clone(&server)).await,
+            "$1\r\nv\r\n"
+        );
+        assert_eq!(
+            run(command(&["TTL", "k"]), Arc::clone(&server)).await,
+            ":-1\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_expire_sets_ttl() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(command(&["SET", "k", "v"]), Arc::clone(&server)).await;
+        assert_eq!(
+            run(command(&["EXPIRE", "k", "50"]), Arc::clone(&server)).await,
+            ":1\r\n"
+        );
+        assert_eq!(
+            run(command(&["TTL", "k"]), Arc::clone(&server)).await,
+            ":50\r\n"
+        );
+        assert_eq!(
+            run(command(&["EXPIRE", "missing", "50"]), Arc::clone(&server)).await,
+            ":0\r\n"
+        );
+        assert_eq!(
+            run(command(&["EXPIRE", "k", "0"]), Arc::clone(&server)).await,
+            ":1\r\n"
+        );
+        assert_eq!(
+            run(command(&["EXISTS", "k"]), Arc::clone(&server)).await,
+            ":0\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_persist_clears_expiry() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(
+            command(&["SET", "k", "v", "PX", "100000"]),
+            Arc::clone(&server),
+        )
+        .await;
+        assert_eq!(
+            run(command(&["PERSIST", "k"]), Arc::clone(&server)).await,
+            ":1\r\n"
+        );
+        assert_eq!(
+            run(command(&["TTL", "k"]), Arc::clone(&server)).await,
+            ":-1\r\n"
+        );
+        assert_eq!(
+            run(command(&["PERSIST", "k"]), Arc::clone(&server)).await,
+            ":0\r\n"
+        );
+    }
+    #[test]
+    fn test_parse_bulk_length_over_limit() {
+        let mut p = Parser::with_limits(b"$11\r\nhello world\r\n", 16, 10);
+        assert_eq!(p.parse_value(), Err(ParseError::InvalidBulkLength));
+        let mut p = Parser::with_limits(b"$10\r\nhello worl\r\n", 16, 10);
+        assert_eq!(
+            p.parse_value(),
+            Ok(Value::BulkString(Bytes::from("hello worl")))
+        );
+    }
+    #[tokio::test]
+    async fn test_large_declared_bulk_length_is_not_preallocated() {
+        let server = Arc::new(Server::new(Config::default()));
+        let header = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$500000000\r\n".to_vec();
+        let mut buffer = header.clone();
+        assert_eq!(
+            handle_buffer(&mut buffer, &server, &mut Client::default()).await,
+            (Vec::new(), true)
+        );
+        assert_eq!(buffer, header);
+        assert!(buffer.capacity() < 1024);
+        let mut buffer = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$600000000\r\n".to_vec();
+        assert_eq!(
+            handle_buffer(&mut buffer, &server, &mut Client::default()).await,
+            (
+                b"-ERR Protocol error: invalid bulk length\r\n".to_vec(),
+                false
+            )
+        );
+    }
+    #[tokio::test]
+    async fn test_set_nx_and_xx() {
+        let server = Arc::new(Server::new(Config::default()));
+        assert_eq!(
+            run(command(&["SET", "k", "v", "xx"]), Arc::clone(&server)).await,
+            "$-1\r\n"
+        );
+        assert_eq!(
+            run(command(&["EXISTS", "k"]), Arc::clone(&server)).await,
+            ":0\r\n"
+        );
+        assert_eq!(
+            run(
+                command(&["SET", "k", "v", "NX", "EX", "10"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "+OK\r\n"
+        );
+        assert_eq!(
+            run(command(&["TTL", "k"]), Arc::clone(&server)).await,
+            ":10\r\n"
+        );
+        assert_eq!(
+            run(command(&["SET", "k", "w", "nx"]), Arc::clone(&server)).await,
+            "$-1\r\n"
+        );
+        assert_eq!(
+            run(
+                command(&["SET", "k", "w", "ex", "20", "XX"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "+OK\r\n"
+        );
+        assert_eq!(
+            run(command(&["GET", "k"]), Arc::clone(&server)).await,
+            "$1\r\nw\r\n"
+        );
+        assert_eq!(
+            run(command(&["SET", "k", "v", "NX", "XX"]), Arc::clone(&server)).await,
+            "-ERR syntax error\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_mset_and_mget() {
+        let server = Arc::new(Server::new(Config::default()));
+        assert_eq!(
+            run(
+                command(&["MSET", "a", "1", "b", "22", "c", "333"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "+OK\r\n"
+        );
+        assert_eq!(
+            run(
+                command(&["MGET", "a", "missing", "b", "c"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "*4\r\n$1\r\n1\r\n$-1\r\n$2\r\n22\r\n$3\r\n333\r\n"
+        );
+        assert_eq!(
+            run(command(&["MSET", "a", "1", "b"]), Arc::clone(&server)).await,
+            "-ERR wrong number of arguments for 'mset' command\r\n"
+        );
+    }
+    #[test]
+    fn test_serialize_round_trip() {
+        let values = [
+            Value::SimpleString("OK".to_string()),
+            Value::BulkString(Bytes::from_static(b"a\r\nb\0")),
+            Value::BulkString(Bytes::new()),
+            Value::Integer(-42),
+            Value::Error("ERR something went wrong".to_string()),
+            Value::Null,
+            Value::Array(vec![]),
+            Value::Array(vec![
+                Value::Integer(1),
+                Value::Null,
+                Value::Array(vec![Value::BulkString(Bytes::from("nested"))]),
+            ]),
+        ];
+        for value in values {
+            let data = serialize(&value);
+            let mut p = Parser::new(&data);
+            assert_eq!(p.parse_value(), Ok(value));
+            assert_eq!(p.pos, data.len());
+        }
+    }
+    #[test]
+    fn test_serialized_len_matches_output() {
+        let values = [
+            Value::SimpleString("OK".to_string()),
+            Value::SimpleString(String::new()),
+            Value::BulkString(Bytes::from(vec![b'x'; 1234])),
+            Value::BulkString(Bytes::new()),
+            Value::Integer(0),
+            Value::Integer(9),
+            Value::Integer(10),
+            Value::Integer(-7),
+            Value::Integer(i64::MAX),
+            Value::Integer(i64::MIN),
+            Value::Error("ERR something went wrong".to_string()),
+            Value::Null,
+            Value::Array(vec![]),
+            Value::Array(
+                (0..12)
+                    .map(|i| Value::Array(vec![Value::Integer(i), Value::Null]))
+                    .collect(),
+            ),
+        ];
+        for value in values {
+            let data = serialize(&value);
+            assert_eq!(value.serialized_len(), data.len(), "{:?}", value);
+        }
+    }
+    #[tokio::test]
+    async fn test_getset_clears_ttl() {
+        let server = Arc::new(Server::new(Config::default()));
+        assert_eq!(
+            run(command(&["GETSET", "k", "v1"]), Arc::clone(&server)).await,
+            "$-1\r\n"
+        );
+        run(command(&["EXPIRE", "k", "100"]), Arc::clone(&server)).await;
+        assert_eq!(
+            run(command(&["GETSET", "k", "v2"]), Arc::clone(&server)).await,
+            "$2\r\nv1\r\n"
+        );
+        assert_eq!(
+            run(command(&["TTL", "k"]), Arc::clone(&server)).await,
+            ":-1\r\n"
+        );
+        run(
+            command(&["SET", "k", "v3", "EX", "100"]),
+            Arc::clone(&server),
+        )
+        .await;
+        run(command(&["SET", "k", "v4", "KEEPTTL"]), Arc::clone(&server)).await;
+        assert_eq!(
+            run(command(&["TTL", "k"]), Arc::clone(&server)).await,
+            ":100\r\n"
+        );
+        run(command(&["SET", "k", "v5"]), Arc::clone(&server)).await;
+        assert_eq!(
+            run(command(&["TTL", "k"]), Arc::clone(&server)).await,
+            ":-1\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_debug_sleep_does_not_block_other_clients() {
+        let addr = spawn_server().await;
+        let mut sleeper = TcpStream::connect(addr).await.expect("connect");
+        let mut client = TcpStream::connect(addr).await.expect("connect");
+        client.write_all(b"SET k v\r\n").await.expect("write");
+        let mut reply = [0; 5];
+        client.read_exact(&mut reply).await.expect("read");
+        sleeper
+            .write_all(b"DEBUG SLEEP 0.5\r\n")
+            .await
+            .expect("write");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let start = std::time::Instant::now();
+        client.write_all(b"GET k\r\n").await.expect("write");
+        let mut reply = [0; 7];
+        client.read_exact(&mut reply).await.expect("read");
+        assert_eq!(&reply, b"$1\r\nv\r\n");
+        assert!(start.elapsed() < Duration::from_millis(200));
+        let mut reply = [0; 5];
+        sleeper.read_exact(&mut reply).await.expect("read");
+        assert_eq!(&reply, b"+OK\r\n");
+    }
+    #[tokio::test]
+    async fn test_debug_sleep_rejects_out_of_range_durations() {
+        let addr = spawn_server().await;
+        let mut client = TcpStream::connect(addr).await.expect("connect");
+        for seconds in ["1e20", "-1", "inf"] {
+            for subcommand in ["SLEEP", "SLEEP-BLOCKING"] {
+                assert_eq!(
+                    send(&mut client, &["DEBUG", subcommand, seconds]).await,
+                    "-ERR value is out of range\r\n",
+                    "{} {}",
+                    subcommand,
+                    seconds
+                );
+            }
+        }
+        assert_eq!(send(&mut client, &["PING"]).await, "+PONG\r\n");
+    }
+    #[tokio::test]
+    async fn test_debug_sleep_blocking_holds_the_store() {
+        let server = Arc::new(Server::new(Config::default()));
+        let sleep = tokio::spawn(run(
+            command(&["DEBUG", "SLEEP-BLOCKING", "0.3"]),
+            Arc::clone(&server),
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let start = std::time::Instant::now();
+        assert_eq!(
+            run(command(&["GET", "k"]), Arc::clone(&server)).await,
+            "$-1\r\n"
+        );
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        assert_eq!(sleep.await.expect("sleep"), "+OK\r\n");
+    }
+    #[tokio::test]
+    async fn test_debug_sleep_blocking_blocks_other_databases() {
+        let addr = spawn_server().await;
+        let mut sleeper = TcpStream::connect(addr).await.expect("connect");
+        let mut client = TcpStream::connect(addr).await.expect("connect");
+        assert_eq!(send(&mut client, &["SELECT", "1"]).await, "+OK\r\n");
+        sleeper
+            .write_all(b"DEBUG SLEEP-BLOCKING 0.3\r\n")
+            .await
+            .expect("write");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let start = std::time::Instant::now();
+        assert_eq!(send(&mut client, &["GET", "k"]).await, "$-1\r\n");
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        let mut reply = [0; 5];
+        sleeper.read_exact(&mut reply).await.expect("read");
+        assert_eq!(&reply, b"+OK\r\n");
+    }
+    #[tokio::test]
+    async fn test_wrong_number_of_arguments() {
+        let server = Arc::new(Server::new(Config::default()));
+        assert_eq!(
+            run(command(&["GET"]), Arc::clone(&server)).await,
+            "-ERR wrong number of arguments for 'get' command\r\n"
+        );
+        assert_eq!(
+            run(command(&["SET", "key"]), Arc::clone(&server)).await,
+            "-ERR wrong number of arguments for 'set' command\r\n"
+        );
+        assert_eq!(
+            run(command(&["GET", "a", "b"]), Arc::clone(&server)).await,
+            "-ERR wrong number of arguments for 'get' command\r\n"
+        );
+        assert_eq!(
+            run(command(&["DEBUG", "object"]), Arc::clone(&server)).await,
+            "-ERR wrong number of arguments for 'debug|object' command\r\n"
+        );
+        assert_eq!(
+            run(command(&["SCRIPT", "LOAD"]), Arc::clone(&server)).await,
+            "-ERR wrong number of arguments for 'script|load' command\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_wrong_arity_keeps_connection_open() {
+        let addr = spawn_server().await;
+        let mut client = TcpStream::connect(addr).await.expect("connect");
+        client
+            .write_all(b"GET\r\nSET key\r\nPING\r\n")
+            .await
+            .expect("write");
+        let expected = b"-ERR wrong number of arguments for 'get' command\r\n\
+            -ERR wrong number of arguments for 'set' command\r\n+PONG\r\n";
+        let mut reply = vec![0; expected.len()];
+        client.read_exact(&mut reply).await.expect("read");
+        assert_eq!(reply, expected);
+    }
+    #[tokio::test]
+    async fn test_active_expire_shrinks_store_without_reads() {
+        let server = Arc::new(Server::new(Config::default()));
+        for i in 0..500 {
+            let key = format!("short:{}", i);
+            run(command(&["SET", &key, "v", "PX", "1"]), Arc::clone(&server)).await;
+        }
+        run(command(&["SET", "long", "v"]), Arc::clone(&server)).await;
+        tokio::spawn(active_expire(Arc::clone(&server)));
+        for _ in 0..50 {
+            if server.dbs[0].read().await.entries.len() == 1 {
+                break;
+            }
+            tokio::time::sleep(ACTIVE_EXPIRE_INTERVAL).await;
+        }
+        let store = server.dbs[0].read().await;
+        assert_eq!(store.entries.len(), 1);
+        assert!(store.entries.contains_key(&b"long"[..]));
+    }
+    #[tokio::test]
+    async fn test_small_integers_are_shared() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(command(&["SET", "k", "100"]), Arc::clone(&server)).await;
+        run(command(&["SET", "other", "100"]), Arc::clone(&server)).await;
+        run(command(&["SET", "big", "10000"]), Arc::clone(&server)).await;
+        run(command(&["SET", "s", "hello"]), Arc::clone(&server)).await;
+        assert_eq!(
+            run(command(&["OBJECT", "REFCOUNT", "k"]), Arc::clone(&server)).await,
+            ":2147483647\r\n"
+        );
+        {
+            let store = server.dbs[0].read().await;
+            assert_eq!(
+                store[&b"k"[..]].string().map(|x| x.as_ptr()),
+                store[&b"other"[..]].string().map(|x| x.as_ptr())
+            );
+        }
+        assert_eq!(
+            run(command(&["OBJECT", "REFCOUNT", "big"]), Arc::clone(&server)).await,
+            ":1\r\n"
+        );
+        assert_eq!(
+            run(command(&["OBJECT", "REFCOUNT", "s"]), Arc::clone(&server)).await,
+            ":1\r\n"
+        );
+        run(command(&["INCR", "s2"]), Arc::clone(&server)).await;
+        run(command(&["INCR", "s2"]), Arc::clone(&server)).await;
+        assert_eq!(
+            run(command(&["OBJECT", "REFCOUNT", "s2"]), Arc::clone(&server)).await,
+            ":2147483647\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_object_encoding_and_errors() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(command(&["SET", "n", "12345"]), Arc::clone(&server)).await;
+        assert_eq!(
+            run(command(&["OBJECT", "ENCODING", "n"]), Arc::clone(&server)).await,
+            "$3\r\nint\r\n"
+        );
+        assert_eq!(
+            run(command(&["OBJECT", "IDLETIME", "n"]), Arc::clone(&server)).await,
+            ":0\r\n"
+        );
+        assert_eq!(
+            run(
+                command(&["OBJECT", "ENCODING", "missing"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "$-1\r\n"
+        );
+        assert_eq!(
+            run(command(&["OBJECT", "refcount"]), Arc::clone(&server)).await,
+            "-ERR wrong number of arguments for 'object|refcount' command\r\n"
+        );
+        assert_eq!(
+            run(command(&["OBJECT", "nope", "n"]), Arc::clone(&server)).await,
+            "-ERR unknown subcommand 'nope'. Try OBJECT HELP.\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_object_encoding_embstr_raw_boundary() {
+        let server = Arc::new(Server::new(Config::default()));
+        let encoding = |key: &'static str| {
+            let server = Arc::clone(&server);
+            async move { text(run(command(&["OBJECT", "ENCODING", key]), server).await) }
+        };
+        run(
+            command(&["SET", "44", &"x".repeat(44)]),
+            Arc::clone(&server),
+        )
+        .await;
+        run(
+            command(&["SET", "45", &"x".repeat(45)]),
+            Arc::clone(&server),
+        )
+        .await;
+        assert_eq!(encoding("44").await, "$6\r\nembstr\r\n");
+        assert_eq!(encoding("45").await, "$3\r\nraw\r\n");
+        run(command(&["APPEND", "short", "ab"]), Arc::clone(&server)).await;
+        assert_eq!(encoding("short").await, "$6\r\nembstr\r\n");
+        run(command(&["APPEND", "short", "c"]), Arc::clone(&server)).await;
+        assert_eq!(encoding("short").await, "$3\r\nraw\r\n");
+        run(command(&["SET", "n", "1"]), Arc::clone(&server)).await;
+        run(command(&["APPEND", "n", "2"]), Arc::clone(&server)).await;
+        assert_eq!(encoding("n").await, "$3\r\nraw\r\n");
+        run(command(&["INCR", "n"]), Arc::clone(&server)).await;
+        assert_eq!(encoding("n").await, "$3\r\nint\r\n");
+        run(command(&["SET", "r", "abc"]), Arc::clone(&server)).await;
+        run(command(&["SETRANGE", "r", "0", "x"]), Arc::clone(&server)).await;
+        assert_eq!(encoding("r").await, "$3\r\nraw\r\n");
+        run(command(&["SETRANGE", "new", "0", "x"]), Arc::clone(&server)).await;
+        assert_eq!(encoding("new").await, "$3\r\nraw\r\n");
+        run(command(&["SET", "r", "abc"]), Arc::clone(&server)).await;
+        assert_eq!(encoding("r").await, "$6\r\nembstr\r\n");
+    }
+    #[tokio::test]
+    async fn test_non_utf8_keys_are_distinct() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(
+            binary_command("SET", &[b"\xff", b"one"]),
+            Arc::clone(&server),
+        )
+        .await;
+        run(
+            binary_command("SET", &[b"\xfe", b"two"]),
+            Arc::clone(&server),
+        )
+        .await;
+        assert_eq!(
+            run(binary_command("GET", &[b"\xff"]), Arc::clone(&server)).await,
+            "$3\r\none\r\n"
+        );
+        assert_eq!(
+            run(binary_command("GET", &[b"\xfe"]), Arc::clone(&server)).await,
+            "$3\r\ntwo\r\n"
+        );
+        assert_eq!(
+            run(
+                binary_command("GET", &["\u{fffd}".as_bytes()]),
+                Arc::clone(&server)
+            )
+            .await,
+            "$-1\r\n"
+        );
+        let reply = run(command(&["KEYS", "*"]), Arc::clone(&server)).await;
+        let mut keys = match Parser::new(&reply).parse_value() {
+            Ok(Value::Array(keys)) => keys.iter().map(extract_bytes).collect::<Vec<_>>(),
+            other => panic!("unexpected reply {:?}", other),
+        };
+        keys.sort();
+        assert_eq!(keys, [&b"\xfe"[..], b"\xff"]);
+    }
+    #[tokio::test]
+    async fn test_keys_matches_pattern() {
+        let server = Arc::new(Server::new(Config::default()));
+        for key in ["user:1", "user:2", "session:1"] {
+            run(command(&["SET", key, "v"]), Arc::clone(&server)).await;
+        }
+        run(
+            command(&["SET", "user:3", "v", "PXAT", "1"]),
+            Arc::clone(&server),
+        )
+        .await;
+        let reply = run(command(&["KEYS", "user:*"]), Arc::clone(&server)).await;
+        let mut p = Parser::new(&reply);
+        let mut keys = match p.parse_value() {
+            Ok(Value::Array(keys)) => keys,
+            other => panic!("unexpected reply {:?}", other),
+        };
+        keys.sort_by_key(extract_string);
+        assert_eq!(
+            keys,
+            vec![
+                Value::BulkString(Bytes::from("user:1")),
+                Value::BulkString(Bytes::from("user:2"))
+            ]
+        );
+        assert_eq!(
+            run(command(&["KEYS", "nothing*"]), Arc::clone(&server)).await,
+            "*0\r\n"
+        );
+    }
+    const PARSER_SEED_CORPUS: &[&[u8]] = &[
+        b"+ABC\r\n",
+        b"+\r\n",
+        b":1000\r\n",
+        b":-42\r\n",
+        b"-ERR unknown command 'FOO'\r\n",
+        b"$10\r\n0123456789\r\n",
+        b"$-1\r\n",
+        b"$6\r\na\r\n\0\xffb\r\n",
+        b"*2\r\n+AB\r\n+CD\r\n",
+        b"*2\r\n$3\r\nGET\r\n$3\r\nKEY\r\n",
+        b"*1\r\n*1\r\n:1\r\n",
+        b"*-1\r\n",
+        b"*abc\r\n",
+        b"PING\n\r\n  ECHO hey\r\n",
+    ];
+    // Xorshift, so the test is reproducible without extra dependencies.
+    fn next_random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+    fn mutate(input: &[u8], state: &mut u64) -> Vec<u8> {
+        let mut data = input.to_vec();
+        for _ in 0..1 + next_random(state) % 4 {
+            let pos = next_random(state) as usize % (data.len() + 1);
+            let byte = b"*$+-:\r\n0123456789-x\0"[next_random(state) as usize % 20];
+            match next_random(state) % 4 {
+                0 if pos < data.len() => data[pos] = byte,
+                1 => data.insert(pos, byte),
+                2 if pos < data.len() => {
+                    data.remove(pos);
+                }
+                _ => data.truncate(pos),
+            }
+        }
+        data
+    }
+    #[test]
+    fn test_parser_never_panics_on_mutated_input() {
+        let mut state = 0x2545f4914f6cdd1d;
+        for i in 0..20000 {
+            let data = if i % 10 == 0 {
+                let len = next_random(&mut state) as usize % 32;
+                (0..len).map(|_| next_random(&mut state) as u8).collect()
+            } else {
+                let seed = PARSER_SEED_CORPUS[i % PARSER_SEED_CORPUS.len()];
+                mutate(seed, &mut state)
+            };
+            let mut p = Parser::with_limits(&data, 1024, 1024);
+            while p.pos < data.len() {
+                let start = p.pos;
+                if p.parse_value().is_err() {
+                    break;
+                }
+                assert!(p.pos > start, "no progress on {:?}", data);
+            }
+        }
+    }
+    #[test]
+    fn test_parser_rejects_deep_nesting() {
+        let data = b"*1\r\n".repeat(100_000);
+        let mut p = Parser::new(&data);
+        assert_eq!(p.parse_value(), Err(ParseError::NestingTooDeep));
+        let data = [b"*1\r\n".repeat(MAX_NESTING_DEPTH), b":1\r\n".to_vec()].concat();
+        assert!(Parser::new(&data).parse_value().is_ok());
+    }
+    #[tokio::test]
+    async fn test_select_switches_database() {
+        let server = Arc::new(Server::new(Config::default()));
+        let mut client = Client::default();
+        let mut other = Client::default();
+        handle_command(
+            command(&["SET", "k", "v"]),
+            Arc::clone(&server),
+            &mut client,
+        )
+        .await;
+        assert_eq!(
+            handle_command(command(&["SELECT", "1"]), Arc::clone(&server), &mut client).await,
+            "+OK\r\n"
+        );
+        assert_eq!(
+            handle_command(command(&["GET", "k"]), Arc::clone(&server), &mut client).await,
+            "$-1\r\n"
+        );
+        handle_command(
+            command(&["SET", "k", "one"]),
+            Arc::clone(&server),
+            &mut client,
+        )
+        .await;
+        assert_eq!(
+            handle_command(command(&["GET", "k"]), Arc::clone(&server), &mut other).await,
+            "$1\r\nv\r\n"
+        );
+        assert_eq!(
+            handle_command(command(&["SELECT", "0"]), Arc::clone(&server), &mut client).await,
+            "+OK\r\n"
+        );
+        assert_eq!(
+            handle_command(command(&["DEL", "k"]), Arc::clone(&server), &mut client).await,
+            ":1\r\n"
+        );
+        assert_eq!(
+            server.dbs[1].read().await[&b"k"[..]].value,
+            StoredData::String(Bytes::from("one"))
+        );
+        assert_eq!(
+            handle_command(command(&["SELECT", "16"]), Arc::clone(&server), &mut client).await,
+            "-ERR DB index is out of range\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_flushdb_and_flushall() {
+        let server = Arc::new(Server::new(Config::default()));
+        let mut client = Client::default();
+        handle_command(
+            command(&["SET", "a", "1"]),
+            Arc::clone(&server),
+            &mut client,
+        )
+        .await;
+        handle_command(command(&["SELECT", "1"]), Arc::clone(&server), &mut client).await;
+        handle_command(
+            command(&["SET", "b", "1"]),
+            Arc::clone(&server),
+            &mut client,
+        )
+        .await;
+        assert_eq!(
+            handle_command(command(&["FLUSHDB"]), Arc::clone(&server), &mut client).await,
+            "+OK\r\n"
+        );
+        assert!(server.dbs[1].read().await.entries.is_empty());
+        assert_eq!(
+            run(command(&["GET", "a"]), Arc::clone(&server)).await,
+            "$1\r\n1\r\n"
+        );
+        handle_command(
+            command(&["SET", "b", "1"]),
+            Arc::clone(&server),
+            &mut client,
+        )
+        .await;
+        assert_eq!(
+            run(command(&["FLUSHALL", "ASYNC"]), Arc::clone(&server)).await,
+            "+OK\r\n"
+        );
+        assert!(server.dbs[0].read().await.entries.is_empty());
+        assert!(server.dbs[1].read().await.entries.is_empty());
+        assert_eq!(
+            run(command(&["FLUSHDB", "LATER"]), Arc::clone(&server)).await,
+            "-ERR syntax error\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_dbsize_excludes_expired_keys() {
+        let server = Arc::new(Server::new(Config::default()));
+        assert_eq!(
+            run(command(&["DBSIZE"]), Arc::clone(&server)).await,
+            ":0\r\n"
+        );
+        run(command(&["SET", "a", "1"]), Arc::clone(&server)).await;
+        run(command(&["SET", "b", "1"]), Arc::clone(&server)).await;
+        run(
+            command(&["SET", "gone", "1", "PXAT", "1"]),
+            Arc::clone(&server),
+        )
+        .await;
+        assert_eq!(server.dbs[0].read().await.entries.len(), 3);
+        assert_eq!(
+            run(command(&["DBSIZE"]), Arc::clone(&server)).await,
+            ":2\r\n"
+        );
+        let mut client = Client::default();
+        handle_command(command(&["SELECT", "1"]), Arc::clone(&server), &mut client).await;
+        assert_eq!(
+            handle_command(command(&["DBSIZE"]), Arc::clone(&server), &mut client).await,
+            ":0\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_redis_cli_handshake() {
+        let server = Arc::new(Server::new(Config::default()));
+        let (output, keep_alive) = handle_buffer(
+            &mut b"*2\r\n$7\r\nCOMMAND\r\n$4\r\nDOCS\r\n".to_vec(),
+            &server,
+            &mut Client::default(),
+        )
+        .await;
+        assert!(keep_alive);
+        let mut p = Parser::new(&output);
+        match p.parse_value() {
+            Ok(Value::Array(docs)) => assert_eq!(docs.len(), COMMANDS.len() * 2),
+            other => panic!("unexpected reply {:?}", other),
+        }
+        assert_eq!(p.pos, output.len());
+        let (output, keep_alive) = handle_buffer(
+            &mut b"*2\r\n$7\r\nCOMMAND\r\n$5\r\nCOUNT\r\n".to_vec(),
+            &server,
+            &mut Client::default(),
+        )
+        .await;
+        assert!(keep_alive);
+        assert_eq!(output, format!(":{}\r\n", COMMANDS.len()).into_bytes());
+    }
+    #[tokio::test]
+    async fn test_hset_and_hget() {
+        let server = Arc::new(Server::new(Config::default()));
+        assert_eq!(
+            run(
+                command(&["HSET", "h", "a", "1", "b", "2"]),
+                Arc::clone(&server)
+            )
+            .await,
+            ":2\r\n"
+        );
+        assert_eq!(
+            run(
+                command(&["HSET", "h", "a", "3", "c", "4"]),
+                Arc::clone(&server)
+            )
+            .await,
+            ":1\r\n"
+        );
+        assert_eq!(
+            run(command(&["HGET", "h", "a"]), Arc::clone(&server)).await,
+            "$1\r\n3\r\n"
+        );
+        assert_eq!(
+            run(command(&["HGET", "h", "missing"]), Arc::clone(&server)).await,
+            "$-1\r\n"
+        );
+        assert_eq!(
+            run(command(&["HGET", "nokey", "a"]), Arc::clone(&server)).await,
+            "$-1\r\n"
+        );
+        assert_eq!(
+            run(command(&["HSET", "h", "a"]), Arc::clone(&server)).await,
+            "-ERR wrong number of arguments for 'hset' command\r\n"
+        );
+        assert_eq!(
+            run(command(&["HSET", "h", "a", "1", "b"]), Arc::clone(&server)).await,
+            "-ERR wrong number of arguments for 'hset' command\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_hash_fields_are_binary_safe() {
+        let server = Arc::new(Server::new(Config::default()));
+        assert_eq!(
+            run(
+                binary_command("HSET", &[b"h", b"\xff", b"a", b"\xfe", b"b"]),
+                Arc::clone(&server)
+            )
+            .await,
+            ":2\r\n"
+        );
+        assert_eq!(
+            run(
+                binary_command("HGET", &[b"h", b"\xff"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "$1\r\na\r\n"
+        );
+        assert_eq!(
+            run(
+                binary_command("HGET", &[b"h", b"\xfe"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "$1\r\nb\r\n"
+        );
+        let reply = run(command(&["HGETALL", "h"]), Arc::clone(&server)).await;
+        assert!(reply.windows(6).any(|x| x == b"$1\r\n\xff\r"));
+    }
+    #[tokio::test]
+    async fn test_hgetall() {
+        let server = Arc::new(Server::new(Config::default()));
+        assert_eq!(
+            run(command(&["HGETALL", "h"]), Arc::clone(&server)).await,
+            "*0\r\n"
+        );
+        run(
+            command(&["HSET", "h", "a", "1", "b", "22"]),
+            Arc::clone(&server),
+        )
+        .await;
+        let reply = run(command(&["HGETALL", "h"]), Arc::clone(&server)).await;
+        let items = match Parser::new(&reply).parse_value() {
+            Ok(Value::Array(items)) => items,
+            other => panic!("unexpected reply {:?}", other),
+        };
+        let mut pairs: Vec<(String, String)> = items
+            .chunks(2)
+            .map(|pair| (extract_string(&pair[0]), extract_string(&pair[1])))
+            .collect();
+        pairs.sort();
+        assert_eq!(
+            pairs,
+            vec![
+                ("a".to_string(), "1".to_string()),
+                ("b".to_string(), "22".to_string())
+            ]
+        );
+    }
+    #[tokio::test]
+    async fn test_hash_and_string_wrongtype() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(command(&["SET", "s", "v"]), Arc::clone(&server)).await;
+        run(command(&["HSET", "h", "f", "v"]), Arc::clone(&server)).await;
+        for args in [
+            &["HGET", "s", "f"][..],
+            &["HGETALL", "s"],
+            &["HSET", "s", "f", "v"],
+            &["GET", "h"],
+            &["GETSET", "h", "v"],
+            &["INCR", "h"],
+            &["PFADD", "h", "x"],
+        ] {
+            assert_eq!(
+                run(command(args), Arc::clone(&server)).await,
+                WRONGTYPE,
+                "{:?}",
+                args
+            );
+        }
+        assert_eq!(
+            run(command(&["MGET", "h", "s"]), Arc::clone(&server)).await,
+            "*2\r\n$-1\r\n$1\r\nv\r\n"
+        );
+        assert_eq!(
+            run(command(&["OBJECT", "ENCODING", "h"]), Arc::clone(&server)).await,
+            "$9\r\nhashtable\r\n"
+        );
+        assert_eq!(
+            run(command(&["SET", "h", "now a string"]), Arc::clone(&server)).await,
+            "+OK\r\n"
+        );
+        assert_eq!(
+            run(command(&["OBJECT", "ENCODING", "h"]), Arc::clone(&server)).await,
+            "$6\r\nembstr\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_rpush_lpush_and_llen() {
+        let server = Arc::new(Server::new(Config::default()));
+        assert_eq!(
+            run(
+                command(&["RPUSH", "mylist", "a", "b", "c"]),
+                Arc::clone(&server)
+            )
+            .await,
+            ":3\r\n"
+        );
+        assert_eq!(
+            run(command(&["LPUSH", "mylist", "y", "z"]), Arc::clone(&server)).await,
+            ":5\r\n"
+        );
+        assert_eq!(
+            run(
+                command(&["LRANGE", "mylist", "0", "-1"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "*5\r\n$1\r\nz\r\n$1\r\ny\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n"
+        );
+        assert_eq!(
+            run(command(&["LLEN", "mylist"]), Arc::clone(&server)).await,
+            ":5\r\n"
+        );
+        assert_eq!(
+            run(command(&["LLEN", "missing"]), Arc::clone(&server)).await,
+            ":0\r\n"
+        );
+        assert_eq!(
+            run(
+                command(&["OBJECT", "ENCODING", "mylist"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "$9\r\nquicklist\r\n"
+        );
+        assert_eq!(
+            run(command(&["RPUSH", "mylist"]), Arc::clone(&server)).await,
+            "-ERR wrong number of arguments for 'rpush' command\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_lrange_negative_indices_and_empty_ranges() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(command(&["RPUSH", "l", "a", "b", "c"]), Arc::clone(&server)).await;
+        for (start, stop, expected) in [
+            ("-2", "-1", "*2\r\n$1\r\nb\r\n$1\r\nc\r\n"),
+            ("-100", "0", "*1\r\n$1\r\na\r\n"),
+            ("1", "100", "*2\r\n$1\r\nb\r\n$1\r\nc\r\n"),
+            ("2", "1", "*0\r\n"),
+            ("5", "10", "*0\r\n"),
+            ("-1", "-3", "*0\r\n"),
+        ] {
+            assert_eq!(
+                run(command(&["LRANGE", "l", start, stop]), Arc::clone(&server)).await,
+                expected,
+                "{} {}",
+                start,
+                stop
+            );
+        }
+        assert_eq!(
+            run(
+                command(&["LRANGE", "missing", "0", "-1"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "*0\r\n"
+        );
+        assert_eq!(
+            run(command(&["LRANGE", "l", "a", "1"]), Arc::clone(&server)).await,
+            "-ERR value is not an integer or out of range\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_list_wrongtype() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(command(&["SET", "s", "v"]), Arc::clone(&server)).await;
+        run(command(&["RPUSH", "l", "a"]), Arc::clone(&server)).await;
+        for args in [
+            &["LPUSH", "s", "x"][..],
+            &["RPUSH", "s", "x"],
+            &["LRANGE", "s", "0", "-1"],
+            &["LLEN", "s"],
+            &["GET", "l"],
+            &["HGET", "l", "f"],
+            &["HSET", "l", "f", "v"],
+            &["INCR", "l"],
+            &["SET", "l", "v", "GET"],
+        ] {
+            assert_eq!(
+                run(command(args), Arc::clone(&server)).await,
+                WRONGTYPE,
+                "{:?}",
+                args
+            );
+        }
+        assert_eq!(
+            run(command(&["LLEN", "l"]), Arc::clone(&server)).await,
+            ":1\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_append_and_strlen() {
+        let server = Arc::new(Server::new(Config::default()));
+        assert_eq!(
+            run(command(&["STRLEN", "k"]), Arc::clone(&server)).await,
+            ":0\r\n"
+        );
+        assert_eq!(
+            run(command(&["APPEND", "k", "Hello"]), Arc::clone(&server)).await,
+            ":5\r\n"
+        );
+        run(command(&["EXPIRE", "k", "100"]), Arc::clone(&server)).await;
+        assert_eq!(
+            run(command(&["APPEND", "k", " World"]), Arc::clone(&server)).await,
+            ":11\r\n"
+        );
+        assert_eq!(
+            run(command(&["GET", "k"]), Arc::clone(&server)).await,
+            "$11\r\nHello World\r\n"
+        );
+        assert_eq!(
+            run(command(&["STRLEN", "k"]), Arc::clone(&server)).await,
+            ":11\r\n"
+        );
+        assert_ne!(
+            run(command(&["TTL", "k"]), Arc::clone(&server)).await,
+            ":-1\r\n"
+        );
+        run(command(&["RPUSH", "l", "a"]), Arc::clone(&server)).await;
+        assert_eq!(
+            run(command(&["APPEND", "l", "x"]), Arc::clone(&server)).await,
+            WRONGTYPE
+        );
+        assert_eq!(
+            run(command(&["STRLEN", "l"]), Arc::clone(&server)).await,
+            WRONGTYPE
+        );
+    }
+    #[tokio::test]
+    async fn test_append_respects_proto_max_bulk_len() {
+        let server = Arc::new(Server::new(Config {
+            proto_max_bulk_len: 8,
+            ..Config::default()
+        }));
+        assert_eq!(
+            run(command(&["APPEND", "k", "12345"]), Arc::clone(&server)).await,
+            ":5\r\n"
+        );
+        assert_eq!(
+            run(command(&["APPEND", "k", "6789"]), Arc::clone(&server)).await,
+            STRING_TOO_LONG
+        );
+        assert_eq!(
+            run(command(&["GET", "k"]), Arc::clone(&server)).await,
+            "$5\r\n12345\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_getrange() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(
+            command(&["SET", "k", "This is a string"]),
+            Arc::clone(&server),
+        )
+        .await;
+        for (start, end, expected) in [
+            ("0", "3", "$4\r\nThis\r\n"),
+            ("-3", "-1", "$3\r\ning\r\n"),
+            ("0", "-1", "$16\r\nThis is a string\r\n"),
+            ("10", "100", "$6\r\nstring\r\n"),
+            ("-100", "1", "$2\r\nTh\r\n"),
+            ("5", "3", "$0\r\n\r\n"),
+            ("-1", "-5", "$0\r\n\r\n"),
+            ("20", "30", "$0\r\n\r\n"),
+        ] {
+            assert_eq!(
+                run(command(&["GETRANGE", "k", start, end]), Arc::clone(&server)).await,
+                expected,
+                "{} {}",
+                start,
+                end
+            );
+        }
+        assert_eq!(
+            run(
+                command(&["GETRANGE", "missing", "0", "-1"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "$0\r\n\r\n"
+        );
+        run(command(&["HSET", "h", "f", "v"]), Arc::clone(&server)).await;
+        assert_eq!(
+            run(command(&["GETRANGE", "h", "0", "-1"]), Arc::clone(&server)).await,
+            WRONGTYPE
+        );
+    }
+    #[tokio::test]
+    async fn test_setrange() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(command(&["SET", "k", "Hello World"]), Arc::clone(&server)).await;
+        assert_eq!(
+            run(
+                command(&["SETRANGE", "k", "6", "Redis"]),
+                Arc::clone(&server)
+            )
+            .await,
+            ":11\r\n"
+        );
+        assert_eq!(
+            run(command(&["GET", "k"]), Arc::clone(&server)).await,
+            "$11\r\nHello Redis\r\n"
+        );
+        assert_eq!(
+            run(command(&["SETRANGE", "k", "9", "s!!"]), Arc::clone(&server)).await,
+            ":12\r\n"
+        );
+        assert_eq!(
+            run(command(&["GET", "k"]), Arc::clone(&server)).await,
+            "$12\r\nHello Reds!!\r\n"
+        );
+        assert_eq!(
+            run(command(&["SETRANGE", "k", "-1", "x"]), Arc::clone(&server)).await,
+            "-ERR offset is out of range\r\n"
+        );
+        assert_eq!(
+            run(
+                command(&["SETRANGE", "missing", "3", ""]),
+                Arc::clone(&server)
+            )
+            .await,
+            ":0\r\n"
+        );
+        assert_eq!(
+            run(command(&["EXISTS", "missing"]), Arc::clone(&server)).await,
+            ":0\r\n"
+        );
+        run(command(&["RPUSH", "l", "a"]), Arc::clone(&server)).await;
+        assert_eq!(
+            run(command(&["SETRANGE", "l", "0", "x"]), Arc::clone(&server)).await,
+            WRONGTYPE
+        );
+    }
+    #[tokio::test]
+    async fn test_setrange_pads_with_zero_bytes() {
+        let server = Arc::new(Server::new(Config::default()));
+        assert_eq!(
+            run(command(&["SETRANGE", "k", "3", "ab"]), Arc::clone(&server)).await,
+            ":5\r\n"
+        );
+        assert_eq!(
+            run(command(&["GET", "k"]), Arc::clone(&server)).await,
+            "$5\r\n\0\0\0ab\r\n"
+        );
+        assert_eq!(
+            run(command(&["SETRANGE", "k", "7", "c"]), Arc::clone(&server)).await,
+            ":8\r\n"
+        );
+        assert_eq!(
+            run(command(&["GET", "k"]), Arc::clone(&server)).await,
+            "$8\r\n\0\0\0ab\0\0c\r\n"
+        );
+        let server = Arc::new(Server::new(Config {
+            proto_max_bulk_len: 8,
+            ..Config::default()
+        }));
+        assert_eq!(
+            run(command(&["SETRANGE", "k", "8", "x"]), Arc::clone(&server)).await,
+            STRING_TOO_LONG
+        );
+    }
+    #[tokio::test]
+    async fn test_set_get_option() {
+        let server = Arc::new(Server::new(Config::default()));
+        assert_eq!(
+            run(command(&["SET", "k", "v1", "GET"]), Arc::clone(&server)).await,
+            "$-1\r\n"
+        );
+        assert_eq!(
+            run(
+                command(&["SET", "k", "v2", "get", "EX", "10"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "$2\r\nv1\r\n"
+        );
+        assert_eq!(
+            run(
+                command(&["SET", "k", "v3", "NX", "GET"]),
+                Arc::clone(&server)
+            )
+            .await,
+            "$2\r\nv2\r\n"
+        );
+        assert_eq!(
+            run(command(&["GET", "k"]), Arc::clone(&server)).await,
+            "$2\r\nv2\r\n"
+        );
+        assert_eq!(
+            run(
+                command(&["SET", "k", "v", "GET", "GET"]),
+                Arc::clone(&server)
             )
+            .await,
+            "-ERR syntax error\r\n"
+        );
+    }
+    #[tokio::test]
+    async fn test_set_get_on_wrong_type_does_not_overwrite() {
+        let server = Arc::new(Server::new(Config::default()));
+        run(command(&["HSET", "k", "f", "x"]), Arc::clone(&server)).await;
+        assert_eq!(
+            run(command(&["SET", "k", "v", "GET"]), Arc::clone(&server)).await,
+            WRONGTYPE
+        );
+        assert_eq!(
+            run(command(&["HGET", "k", "f"]), Arc::clone(&server)).await,
+            "$1\r\nx\r\n"
+        );
+        assert_eq!(
+            run(command(&["SET", "k", "v"]), Arc::clone(&server)).await,
+            "+OK\r\n"
         );
     }
 }